@@ -0,0 +1,83 @@
+use crate::{config::Config, http::Domain, metrics::METRICS, store::DomainStore};
+use anyhow::Error;
+use lru::LruCache;
+use std::{
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+struct Entry {
+    /// `None` remembers that the name is not registered
+    domain: Option<Domain>,
+    stored: Instant,
+}
+
+struct Domains {
+    entries: LruCache<(String, String), Entry>,
+    /// Bumped on every change, lookups racing with one are not kept
+    generation: u64,
+}
+
+/// Recent domain lookups by name and tld, dropped when the domain changes
+pub(crate) struct Cache {
+    domains: Mutex<Domains>,
+}
+
+impl Cache {
+    pub(crate) fn new(config: &Config) -> Self {
+        let capacity = NonZeroUsize::new(config.settings.cache.capacity).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            domains: Mutex::new(Domains {
+                entries: LruCache::new(capacity),
+                generation: 0,
+            }),
+        }
+    }
+
+    /// Find `name` on `tld`, from memory when it was looked up less than `cache.ttl` seconds ago
+    pub(crate) async fn find(&self, name: &str, tld: &str, db: &dyn DomainStore, config: &Config) -> Result<Option<Domain>, Error> {
+        let settings = &config.settings.cache;
+        let Some(capacity) = NonZeroUsize::new(settings.capacity).filter(|_| settings.ttl > 0) else {
+            return db.find(name, tld).await;
+        };
+
+        let key = (name.to_string(), tld.to_string());
+        let ttl = Duration::from_secs(settings.ttl);
+
+        let generation = {
+            let mut domains = self.domains.lock().unwrap_or_else(|err| err.into_inner());
+
+            // the capacity may have changed with a config reload
+            if domains.entries.cap() != capacity {
+                domains.entries.resize(capacity);
+            }
+
+            if let Some(entry) = domains.entries.get(&key).filter(|entry| entry.stored.elapsed() < ttl) {
+                METRICS.cache.with_label_values(&["hit"]).inc();
+                return Ok(entry.domain.clone());
+            }
+
+            domains.generation
+        };
+
+        METRICS.cache.with_label_values(&["miss"]).inc();
+        let stored = Instant::now();
+        let domain = db.find(name, tld).await?;
+
+        let mut domains = self.domains.lock().unwrap_or_else(|err| err.into_inner());
+        if domains.generation == generation {
+            domains.entries.put(key, Entry { domain: domain.clone(), stored });
+        }
+
+        Ok(domain)
+    }
+
+    /// Drop the lookup of `name` on `tld`, call after every change to the domain
+    pub(crate) fn forget(&self, name: &str, tld: &str) {
+        let mut domains = self.domains.lock().unwrap_or_else(|err| err.into_inner());
+
+        domains.entries.pop(&(name.to_string(), tld.to_string()));
+        domains.generation += 1;
+    }
+}
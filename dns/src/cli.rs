@@ -1,46 +1,168 @@
-use crate::{config::Config, kv, secret, Cli};
+use crate::{
+    config::Config,
+    kv::{self, Scope},
+    store::DomainStore,
+    time,
+    zone::{self, Action, Conflict, Format},
+    Cli,
+};
 use colored::Colorize;
 use macros_rs::fmt::{crashln, string};
+use std::{fs::File, sync::Arc};
+
+fn key_db(config: &Config) -> sled::Db {
+    match kv::open(&config.server.key_db) {
+        Ok(db) => db,
+        Err(err) => crashln!("{}", string!(err).white()),
+    }
+}
 
 pub fn list(cli: &Cli) {
     let config = Config::new().set_path(&cli.config).read();
 
-    if let Err(err) = kv::list(&config.server.key_db, false) {
+    if let Err(err) = kv::list(&key_db(&config), false) {
         crashln!("Failed to list: {}", string!(err).white());
     };
 }
 
-pub fn create(cli: &Cli, name: &String) {
-    let key = secret::generate(60);
+pub fn create(cli: &Cli, name: &str, scopes: &[Scope], expires: Option<u64>, description: &str) {
     let config = Config::new().set_path(&cli.config).read();
 
-    match kv::set(&config.server.key_db, name, &key) {
-        Ok(_) => log::info!("{}\n - name: {}\n - key: {}", "Created key".white(), name.magenta(), key.green()),
+    match kv::create(&key_db(&config), name, scopes.to_vec(), expires, description.to_string()) {
+        Ok((key, api_key)) => log::info!(
+            "{}\n - name: {}\n - key: {}\n - scopes: {}\n - expires: {}",
+            "Created key".white(),
+            name.magenta(),
+            key.green(),
+            api_key.scope_list(),
+            time::format(api_key.expires)
+        ),
         Err(err) => crashln!("Failed to create: {}", string!(err).white()),
     };
 }
 
-pub fn remove(cli: &Cli, name: &String) {
+pub fn remove(cli: &Cli, name: &str) {
     let config = Config::new().set_path(&cli.config).read();
 
-    match kv::remove(&config.server.key_db, name) {
+    match kv::remove(&key_db(&config), name) {
         Ok(_) => log::info!("{} {}", "Deleted key".red(), name.bright_red()),
         Err(err) => crashln!("Failed to delete: {}", string!(err).white()),
     };
 }
 
-pub fn info(cli: &Cli, name: &String) {
+pub fn info(cli: &Cli, name: &str) {
     let config = Config::new().set_path(&cli.config).read();
-    let key = kv::get(&config.server.key_db, name);
 
-    log::info!("{}: {key:?}", name.yellow());
+    match kv::get(&key_db(&config), name) {
+        Ok(key) => log::info!(
+            "{}\n - scopes: {}\n - description: {}\n - created: {}\n - expires: {}{}\n - last used: {}",
+            name.yellow(),
+            key.scope_list(),
+            key.description,
+            time::format(key.created()),
+            time::format(key.expires),
+            if key.is_expired() { " (expired)".red() } else { "".normal() },
+            time::format(key.last_used)
+        ),
+        Err(err) => crashln!("Failed to fetch: {}", string!(err).white()),
+    };
 }
 
-pub fn export(cli: &Cli, filename: &String) {
+pub fn export(cli: &Cli, filename: &str) {
     let config = Config::new().set_path(&cli.config).read();
+    let out = match File::create(filename) {
+        Ok(file) => file,
+        Err(err) => crashln!("Failed to export: {}", string!(err).white()),
+    };
 
-    match kv::save(&config.server.key_db, filename) {
+    match kv::save(&key_db(&config), out) {
         Ok(_) => log::info!("Exported keys to {}", filename.green()),
         Err(err) => crashln!("Failed to export: {}", string!(err).white()),
     }
 }
+
+#[tokio::main]
+pub async fn migrate(cli: &Cli) {
+    let config = Config::new().set_path(&cli.config).read();
+
+    match kv::migrate(&key_db(&config)) {
+        Ok(count) => log::info!("Hashed {} API keys", count.to_string().green()),
+        Err(err) => crashln!("Failed to migrate API keys: {}", string!(err).white()),
+    };
+
+    match store(&config).await.migrate_secrets().await {
+        Ok(count) => log::info!("Hashed {} domain keys and owner tokens", count.to_string().green()),
+        Err(err) => crashln!("Failed to migrate domain keys: {}", string!(err).white()),
+    };
+}
+
+async fn store(config: &Config) -> Arc<dyn DomainStore> {
+    match config.connect_store().await {
+        Ok(store) => store,
+        Err(err) => crashln!("Failed to connect to storage backend.\n{}", string!(err).white()),
+    }
+}
+
+#[tokio::main]
+pub async fn zone_export(cli: &Cli, path: &str, format: Format, tld: Option<&str>) {
+    let config = Config::new().set_path(&cli.config).read();
+    let db = store(&config).await;
+
+    let domains = match zone::domains(db.as_ref(), tld).await {
+        Ok(domains) => domains,
+        Err(err) => crashln!("Failed to read domains: {}", string!(err).white()),
+    };
+
+    let written = match format {
+        Format::Jsonl => zone::write_jsonl(&domains, path).map(|_| vec![path.to_string()]),
+        Format::Bind => zone::write_bind(&domains, path, &config),
+    };
+
+    match written {
+        Ok(files) if files.is_empty() => log::warn!("No domains to export"),
+        Ok(files) => log::info!("Exported {} domains to {}", domains.len().to_string().green(), files.join(", ").green()),
+        Err(err) => crashln!("Failed to export: {}", string!(err).white()),
+    }
+}
+
+#[tokio::main]
+pub async fn zone_import(cli: &Cli, path: &str, format: Format, conflict: Conflict, dry_run: bool) {
+    let config = Config::new().set_path(&cli.config).read();
+    let db = store(&config).await;
+
+    let domains = match format {
+        Format::Jsonl => zone::read_jsonl(path),
+        Format::Bind => zone::zone_files(path).and_then(|files| files.iter().map(|file| zone::read_bind(file)).collect::<Result<Vec<_>, _>>().map(|zones| zones.concat())),
+    };
+
+    let domains = match domains {
+        Ok(domains) => domains,
+        Err(err) => crashln!("Failed to read {}: {}", path, string!(err).white()),
+    };
+
+    let planned = match zone::plan(&config, db.as_ref(), domains, conflict).await {
+        Ok(planned) => planned,
+        Err(err) => crashln!("Nothing was imported: {}", string!(err).white()),
+    };
+
+    let count = |action: Action| planned.iter().filter(|planned| planned.action == action).count().to_string();
+    let summary = format!("{} new, {} overwritten, {} skipped", count(Action::Create).green(), count(Action::Overwrite).yellow(), count(Action::Skip).white());
+
+    if dry_run {
+        for planned in &planned {
+            log::info!("{:?} {}.{}", planned.action, planned.domain.name, planned.domain.tld);
+        }
+        return log::info!("Dry run, would import {summary}");
+    }
+
+    match zone::apply(&config, db, planned, format).await {
+        Ok(keys) => {
+            // keys of domains imported without one are only shown here, print them to stdout so they can be saved
+            for (domain, key) in keys {
+                println!("{domain} {key}");
+            }
+            log::info!("Imported {summary}");
+        }
+        Err(err) => crashln!("Failed to import: {}", string!(err).white()),
+    }
+}
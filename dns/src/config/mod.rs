@@ -1,18 +1,157 @@
 mod file;
 mod structs;
+mod watch;
 
-use crate::http::Domain;
+use crate::store::{DomainStore, EmbeddedStore, MongoStore};
+use anyhow::{anyhow, Error};
+use arc_swap::ArcSwap;
 use colored::Colorize;
 use macros_rs::fmt::{crashln, string};
-use mongodb::{error::Error, options::ClientOptions, Client, Collection};
-use std::fs::write;
-use structs::{Mongo, Server, Settings};
+use ipnet::IpNet;
+use std::{fs::write, net::IpAddr, sync::Arc};
+use structs::{Backend, Cache, Dns, Mongo, Proxy, Reclaim, Server, Settings, Storage, Verification};
+
+pub(crate) use structs::{Charset, LimitBy, Naming, RateLimit, RateLimits, Registration, Tld, Webhook};
 
 pub use structs::Config;
+pub(crate) use watch::watch;
+
+/// The running configuration, swapped as a whole when `config.toml` is reloaded
+pub(crate) type SharedConfig = Arc<ArcSwap<Config>>;
+
+pub(crate) const DAY: u64 = 86400;
+
+/// Days an expired domain can be renewed when its tld does not set `grace`
+const DEFAULT_GRACE: u64 = 30;
+
+/// Longest `expiry` and `grace` a tld can set, far enough out to never overflow a timestamp
+const MAX_DAYS: u64 = 36500;
+
+/// Parse a CIDR, a bare address being a network of one
+fn parse_net(value: &str) -> Option<IpNet> { value.parse().ok().or_else(|| value.parse::<IpAddr>().ok().map(IpNet::from)) }
+
+impl Default for Dns {
+    fn default() -> Self {
+        Dns {
+            enabled: false,
+            address: "0.0.0.0".into(),
+            port: 53,
+            ttl: 300,
+            nameserver: "localhost".into(),
+            hostmaster: "hostmaster.localhost".into(),
+        }
+    }
+}
+
+impl Default for Proxy {
+    fn default() -> Self {
+        Proxy {
+            trusted: Vec::new(),
+            protocol: false,
+            ipv6_prefix: 64,
+        }
+    }
+}
+
+impl Default for Reclaim {
+    fn default() -> Self {
+        Reclaim {
+            interval: 3600,
+            health_check: false,
+            health_timeout: 5,
+        }
+    }
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache {
+            capacity: 10_000,
+            ttl: 30,
+            max_age: 30,
+        }
+    }
+}
+
+impl Default for Verification {
+    fn default() -> Self {
+        Verification {
+            timeout: 10,
+            github_raw: "https://raw.githubusercontent.com".into(),
+            allow_private: false,
+        }
+    }
+}
+
+impl Tld {
+    /// Whether `name`, in its stored punycode form, is reserved by this policy
+    pub fn reserves(&self, name: &str) -> bool { self.reserved.iter().any(|reserved| crate::naming::to_ascii(reserved) == name) }
+
+    /// Seconds a registration or renewal lasts, `None` when domains never expire
+    pub fn lifetime(&self) -> Option<u64> { self.expiry.map(|days| days.saturating_mul(DAY)) }
+
+    /// Seconds an expired domain is kept for renewal before it is released
+    pub fn grace_period(&self) -> u64 { self.grace.unwrap_or(DEFAULT_GRACE).saturating_mul(DAY) }
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Storage {
+            backend: Backend::Mongo,
+            path: "registry".into(),
+        }
+    }
+}
+
+impl Default for Naming {
+    fn default() -> Self {
+        Naming {
+            charset: Charset::Unicode,
+            digits: false,
+            min_length: 1,
+            max_length: 24,
+            leading_hyphen: false,
+            trailing_hyphen: false,
+            double_hyphen: false,
+            confusables: true,
+        }
+    }
+}
+
+impl RateLimits {
+    fn limit(requests: u32, period: u64) -> RateLimit {
+        RateLimit {
+            requests,
+            period,
+            by: LimitBy::Ip,
+            keys: Default::default(),
+        }
+    }
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits(
+            [
+                ("POST /domain", Self::limit(5, 600)),
+                ("POST /owner", Self::limit(5, 600)),
+                ("PUT /domain/{key}", Self::limit(30, 60)),
+                ("DELETE /domain/{key}", Self::limit(10, 60)),
+                ("POST /domain/check", Self::limit(60, 60)),
+                ("GET /domains", Self::limit(60, 60)),
+                ("POST /domains/resolve", Self::limit(60, 60)),
+                ("POST /domain/{key}/verification/check", Self::limit(10, 60)),
+            ]
+            .into_iter()
+            .map(|(route, limit)| (route.to_string(), limit))
+            .collect(),
+        )
+    }
+}
 
 impl Config {
     pub fn new() -> Self {
-        let default_offensive_words = vec!["nigg", "sex", "porn", "igg"];
+        let default_offensive_words = ["nigg", "sex", "porn", "igg"];
         let default_tld_list = vec![
             "mf", "btw", "fr", "yap", "dev", "scam", "zip", "root", "web", "rizz", "habibi", "sigma", "now", "it", "soy", "lol", "uwu", "ohio", "cat",
         ];
@@ -23,6 +162,9 @@ impl Config {
                 address: "127.0.0.1".into(),
                 port: 8080,
                 key_db: "domains".into(),
+                storage: Storage::default(),
+                dns: Dns::default(),
+                proxy: Proxy::default(),
                 mongo: Mongo {
                     connection: "".into(),
                     app_name: "DomainApp".into(),
@@ -32,18 +174,133 @@ impl Config {
             settings: Settings {
                 tld_list: default_tld_list.iter().map(|s| s.to_string()).collect(),
                 offensive_words: default_offensive_words.iter().map(|s| s.to_string()).collect(),
+                naming: Naming::default(),
+                tlds: Default::default(),
+                ratelimits: RateLimits::default(),
+                reclaim: Reclaim::default(),
+                cache: Cache::default(),
+                verification: Verification::default(),
+                event_retention: 30,
+                webhooks: Vec::new(),
             },
         }
     }
 
-    pub fn read(&self) -> Self { file::read(&self.config_path) }
+    pub fn read(&self) -> Self {
+        match self.try_read() {
+            Ok(config) => config,
+            Err(err) => crashln!("{}", string!(err).white()),
+        }
+    }
+
+    /// Read and validate the config file without exiting on errors
+    pub fn try_read(&self) -> Result<Self, Error> {
+        let mut config: Config = file::try_read(&self.config_path)?;
+        config.config_path = self.config_path.clone();
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        for tld in self.tld_list() {
+            if tld.is_empty() || !tld.chars().all(|c| matches!(c, 'a'..='z' | '0'..='9' | '-')) {
+                return Err(anyhow!("Invalid config.\nTLD '{tld}' must be made of a-z, 0-9 or '-'"));
+            }
+
+            if self.settings.tlds.get(tld).is_some_and(|policy| policy.expiry == Some(0)) {
+                return Err(anyhow!("Invalid config.\nExpiry of '{tld}' must be above 0 days, leave it unset for domains that never expire"));
+            }
+
+            if self.settings.tlds.get(tld).is_some_and(|policy| policy.expiry.max(policy.grace).is_some_and(|days| days > MAX_DAYS)) {
+                return Err(anyhow!("Invalid config.\nExpiry and grace of '{tld}' can be at most {MAX_DAYS} days"));
+            }
+
+            let naming = self.naming_for(tld);
+            if naming.min_length == 0 || naming.min_length > naming.max_length {
+                return Err(anyhow!("Invalid config.\nNaming rules of '{tld}' need 0 < min_length <= max_length"));
+            }
+        }
+
+        if self.settings.reclaim.interval == 0 || self.settings.reclaim.health_timeout == 0 {
+            return Err(anyhow!("Invalid config.\nReclaim interval and health_timeout must be above 0"));
+        }
+
+        for hook in &self.settings.webhooks {
+            if !(hook.url.starts_with("http://") || hook.url.starts_with("https://")) || hook.secret.is_empty() {
+                return Err(anyhow!("Invalid config.\nWebhook '{}' needs an http(s) url and a secret", hook.url));
+            }
+        }
+
+        for proxy in &self.server.proxy.trusted {
+            if parse_net(proxy).is_none() {
+                return Err(anyhow!("Invalid config.\nTrusted proxy '{proxy}' is not an address or CIDR"));
+            }
+        }
+
+        for host in [&self.server.dns.nameserver, &self.server.dns.hostmaster] {
+            if host.trim_end_matches('.').split('.').any(|label| label.is_empty() || !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')) {
+                return Err(anyhow!("Invalid config.\nDNS nameserver and hostmaster must be host names, '{host}' is not"));
+            }
+        }
+
+        if self.server.proxy.ipv6_prefix > 128 {
+            return Err(anyhow!("Invalid config.\nipv6_prefix can be at most 128"));
+        }
+
+        for (route, limit) in &self.settings.ratelimits.0 {
+            if !route.split_once(' ').is_some_and(|(method, path)| method.chars().all(|c| c.is_ascii_uppercase()) && path.starts_with('/')) {
+                return Err(anyhow!("Invalid config.\nRate limit route '{route}' must look like 'POST /domain'"));
+            }
+
+            if limit.requests == 0 || limit.period == 0 || limit.keys.values().any(|requests| *requests == 0) {
+                return Err(anyhow!("Invalid config.\nRate limit of '{route}' needs requests and period above 0"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether `ip` is a proxy trusted to forward client addresses,
+    /// without a `trusted` list only `server.address` is
+    pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        match self.server.proxy.trusted.is_empty() {
+            true => self.server.address.parse() == Ok(ip),
+            false => self.server.proxy.trusted.iter().filter_map(|proxy| parse_net(proxy)).any(|net| net.contains(&ip)),
+        }
+    }
+
+    /// Rate limit of `route`, given as `METHOD /pattern`
+    pub fn ratelimit(&self, route: &str) -> Option<&RateLimit> { self.settings.ratelimits.0.get(route) }
+
+    pub fn shared(self) -> SharedConfig { Arc::new(ArcSwap::from_pointee(self)) }
     pub fn get_address(&self) -> String { format!("{}:{}", self.server.address.clone(), self.server.port) }
-    pub fn tld_list(&self) -> Vec<&str> { self.settings.tld_list.iter().map(AsRef::as_ref).collect::<Vec<&str>>() }
+    pub fn get_dns_address(&self) -> String { format!("{}:{}", self.server.dns.address.clone(), self.server.dns.port) }
+
+    /// Every tld, those of `tld_list` followed by the ones only given a policy
+    pub fn tld_list(&self) -> Vec<&str> {
+        let extra = self.settings.tlds.keys().filter(|tld| !self.settings.tld_list.contains(tld));
+        self.settings.tld_list.iter().chain(extra).map(AsRef::as_ref).collect::<Vec<&str>>()
+    }
+
+    /// Policy of `tld`, tlds from `tld_list` without one are open to everyone
+    pub fn tld(&self, tld: &str) -> Option<Tld> {
+        match self.settings.tlds.get(tld) {
+            Some(policy) => Some(policy.clone()),
+            None => self.settings.tld_list.iter().any(|name| name == tld).then(Tld::default),
+        }
+    }
+
+    /// Seconds an expired domain on `tld` is kept, tlds removed from the config use the default
+    pub fn grace_for(&self, tld: &str) -> u64 { self.tld(tld).unwrap_or_default().grace_period() }
+
+    /// Naming rules of `tld`, falling back to `[settings.naming]`
+    pub fn naming_for(&self, tld: &str) -> Naming { self.settings.tlds.get(tld).and_then(|policy| policy.naming.clone()).unwrap_or_else(|| self.settings.naming.clone()) }
     pub fn offen_words(&self) -> Vec<&str> { self.settings.offensive_words.iter().map(AsRef::as_ref).collect::<Vec<&str>>() }
 
-    pub fn set_path(&mut self, config_path: &String) -> &mut Self {
-        self.config_path = config_path.clone();
-        return self;
+    pub fn set_path(&mut self, config_path: &str) -> &mut Self {
+        self.config_path = config_path.to_owned();
+        self
     }
 
     pub fn write(&self) -> &Self {
@@ -58,18 +315,58 @@ impl Config {
 
         log::info!("Created config: {}", &self.config_path,);
 
-        return self;
+        self
+    }
+
+    pub async fn connect_store(&self) -> Result<Arc<dyn DomainStore>, Error> {
+        Ok(match self.server.storage.backend {
+            Backend::Mongo => Arc::new(MongoStore::connect(self).await?),
+            Backend::Embedded => Arc::new(EmbeddedStore::open(&self.server.storage.path)?),
+        })
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    pub async fn connect_to_mongo(&self) -> Result<Collection<Domain>, Error> {
-        let mut client_options = ClientOptions::parse(&self.server.mongo.connection).await?;
-        client_options.app_name = Some(self.server.mongo.app_name.clone());
+    fn invalid(change: impl FnOnce(&mut Config)) -> String {
+        let mut config = Config::new();
+        change(&mut config);
+        config.validate().unwrap_err().to_string()
+    }
 
-        let client = Client::with_options(client_options)?;
-        let db = client.database(&self.server.mongo.db_name);
-        let collection = db.collection::<Domain>("domains");
+    #[test]
+    fn defaults_are_valid() { assert!(Config::new().validate().is_ok()) }
+
+    #[test]
+    fn invalid_settings_are_refused() {
+        let errors = [
+            invalid(|config| config.settings.tld_list.push("Bad!".into())),
+            invalid(|config| {
+                config.settings.tlds.insert("dev".into(), Tld { expiry: Some(0), ..Tld::default() });
+            }),
+            invalid(|config| {
+                config.settings.tlds.insert("dev".into(), Tld { grace: Some(u64::MAX), ..Tld::default() });
+            }),
+            invalid(|config| config.settings.naming.min_length = 0),
+            invalid(|config| config.settings.reclaim.interval = 0),
+            invalid(|config| config.server.proxy.trusted.push("not an address".into())),
+            invalid(|config| config.server.proxy.ipv6_prefix = 129),
+            invalid(|config| config.server.dns.hostmaster = "hostmaster@example.com".into()),
+            invalid(|config| {
+                config.settings.ratelimits.0.insert("post /domain".into(), RateLimits::limit(1, 1));
+            }),
+        ];
+
+        for error in errors {
+            assert!(error.starts_with("Invalid config.\n") && error.matches('\n').count() == 1, "{error:?}");
+        }
+    }
 
-        log::info!("MongoDB server connected");
-        Ok(collection)
+    #[test]
+    fn naming_rules_need_a_valid_range() {
+        let error = invalid(|config| config.settings.naming.max_length = 0);
+        assert_eq!(error, "Invalid config.\nNaming rules of 'mf' need 0 < min_length <= max_length");
     }
 }
@@ -1,4 +1,6 @@
+use crate::events::EventKind;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Config {
@@ -12,11 +14,56 @@ pub struct Config {
 pub struct Server {
     pub(crate) address: String,
     pub(crate) port: u64,
+    #[serde(default)]
+    pub(crate) storage: Storage,
+    #[serde(default)]
     pub(crate) mongo: Mongo,
+    #[serde(default)]
+    pub(crate) dns: Dns,
+    #[serde(default)]
+    pub(crate) proxy: Proxy,
     pub(crate) key_db: String,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Proxy {
+    /// Addresses or CIDRs of proxies allowed to forward client addresses
+    pub(crate) trusted: Vec<String>,
+    /// Expect a PROXY protocol v1/v2 header from trusted proxies
+    pub(crate) protocol: bool,
+    /// IPv6 clients sharing this prefix share their rate limits
+    pub(crate) ipv6_prefix: u8,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Dns {
+    pub(crate) enabled: bool,
+    pub(crate) address: String,
+    pub(crate) port: u64,
+    pub(crate) ttl: u32,
+    /// Host name of the nameserver, named in the SOA and NS records of every tld
+    pub(crate) nameserver: String,
+    /// Mailbox of the zone administrator in SOA form, `hostmaster.example.com` for hostmaster@example.com
+    pub(crate) hostmaster: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    Mongo,
+    Embedded,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Storage {
+    pub(crate) backend: Backend,
+    pub(crate) path: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Mongo {
     pub(crate) connection: String,
     pub(crate) app_name: String,
@@ -25,6 +72,151 @@ pub struct Mongo {
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Settings {
+    #[serde(default)]
     pub(crate) tld_list: Vec<String>,
     pub(crate) offensive_words: Vec<String>,
+    #[serde(default)]
+    pub(crate) naming: Naming,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) tlds: BTreeMap<String, Tld>,
+    #[serde(default)]
+    pub(crate) ratelimits: RateLimits,
+    #[serde(default)]
+    pub(crate) reclaim: Reclaim,
+    #[serde(default)]
+    pub(crate) cache: Cache,
+    #[serde(default)]
+    pub(crate) verification: Verification,
+    /// Days events are kept for subscribers catching up
+    #[serde(default = "event_retention")]
+    pub(crate) event_retention: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) webhooks: Vec<Webhook>,
+}
+
+fn event_retention() -> u64 { 30 }
+
+/// An endpoint receiving registry events, signed with `secret`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Webhook {
+    pub(crate) url: String,
+    pub(crate) secret: String,
+    /// Events to deliver, every event when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) events: Vec<EventKind>,
+}
+
+/// Background release of expired domains and health checks of their targets
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Reclaim {
+    /// Seconds between runs
+    pub(crate) interval: u64,
+    /// Flag domains whose target does not accept connections
+    pub(crate) health_check: bool,
+    /// Seconds to wait for a target to accept a connection
+    pub(crate) health_timeout: u64,
+}
+
+/// Lookups kept in memory, and how long clients may keep them
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Cache {
+    /// Domains kept, 0 disables the cache
+    pub(crate) capacity: usize,
+    /// Seconds a lookup is served from memory
+    pub(crate) ttl: u64,
+    /// Seconds clients and proxies may reuse a response without revalidating
+    pub(crate) max_age: u64,
+}
+
+/// Fetching of the tokens proving control over URL targets
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Verification {
+    /// Seconds a target gets to serve its token
+    pub(crate) timeout: u64,
+    /// Where the tokens of GitHub repositories are read from
+    pub(crate) github_raw: String,
+    /// Fetch from loopback, private and link-local addresses, only meant for testing
+    pub(crate) allow_private: bool,
+}
+
+/// Rate limits keyed by route, e.g. `"POST /domain"`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct RateLimits(pub(crate) BTreeMap<String, RateLimit>);
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitBy {
+    /// The client address, see `RealIpKeyExtractor`
+    #[default]
+    Ip,
+    /// The API key named in the `authorization` header, the address without one
+    Key,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RateLimit {
+    pub(crate) requests: u32,
+    pub(crate) period: u64,
+    #[serde(default)]
+    pub(crate) by: LimitBy,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) keys: BTreeMap<String, u32>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Registration {
+    /// Anyone can register
+    #[default]
+    Open,
+    /// Only API keys with the `create` scope can register
+    Invite,
+    /// Nobody can register
+    Closed,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Tld {
+    pub(crate) registration: Registration,
+    pub(crate) description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) quota: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) reserved: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) naming: Option<Naming>,
+    /// Days a registration lasts before it has to be renewed, forever when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) expiry: Option<u64>,
+    /// Days an expired domain can still be renewed before it is released
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) grace: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Charset {
+    /// Only `a-z`
+    Ascii,
+    /// Letters of any script, stored as punycode
+    #[default]
+    Unicode,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Naming {
+    pub(crate) charset: Charset,
+    pub(crate) digits: bool,
+    pub(crate) min_length: usize,
+    pub(crate) max_length: usize,
+    pub(crate) leading_hyphen: bool,
+    pub(crate) trailing_hyphen: bool,
+    pub(crate) double_hyphen: bool,
+    pub(crate) confusables: bool,
 }
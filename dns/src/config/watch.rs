@@ -0,0 +1,114 @@
+use super::{Config, SharedConfig};
+use sha2::{Digest, Sha256};
+use std::{fs, sync::Arc, time::Duration, time::SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use toml::Value;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// `[server]` settings read on every request or query instead of at startup
+const RELOADABLE: [&str; 3] = ["server.dns.ttl", "server.proxy.trusted", "server.proxy.ipv6_prefix"];
+
+fn modified(path: &str) -> Option<SystemTime> { fs::metadata(path).and_then(|meta| meta.modified()).ok() }
+
+/// Flatten a config into `dotted.path = value` pairs, arrays of tables as `path[index]`
+fn flatten(prefix: &str, value: &Value, out: &mut Vec<(String, String)>) {
+    match value {
+        Value::Table(table) => {
+            for (key, value) in table {
+                let path = match prefix.is_empty() {
+                    true => key.clone(),
+                    false => format!("{prefix}.{key}"),
+                };
+                flatten(&path, value, out);
+            }
+        }
+        Value::Array(items) if !items.is_empty() && items.iter().all(Value::is_table) => {
+            for (index, item) in items.iter().enumerate() {
+                flatten(&format!("{prefix}[{index}]"), item, out);
+            }
+        }
+        // secrets are compared by a short hash so the log shows that they changed without leaking them
+        value if prefix.ends_with(".secret") => out.push((prefix.to_string(), redact(&value.to_string()))),
+        value => out.push((prefix.to_string(), value.to_string())),
+    }
+}
+
+fn redact(secret: &str) -> String {
+    let digest = Sha256::digest(secret.as_bytes());
+    format!("<redacted {}>", hex::encode(&digest[..4]))
+}
+
+/// Settings that differ between two configs, as `path: old -> new`
+pub fn diff(old: &Config, new: &Config) -> Vec<String> {
+    let (mut before, mut after) = (Vec::new(), Vec::new());
+
+    if let (Ok(old), Ok(new)) = (Value::try_from(old), Value::try_from(new)) {
+        flatten("", &old, &mut before);
+        flatten("", &new, &mut after);
+    }
+
+    let mut changes: Vec<String> = after
+        .iter()
+        .filter(|entry| !before.contains(entry))
+        .map(|(path, value)| match before.iter().find(|(old, _)| old == path) {
+            Some((_, old)) => format!("{path}: {old} -> {value}"),
+            None => format!("{path}: (unset) -> {value}"),
+        })
+        .collect();
+
+    changes.extend(before.iter().filter(|(path, _)| !after.iter().any(|(new, _)| new == path)).map(|(path, old)| format!("{path}: {old} -> (unset)")));
+    changes
+}
+
+fn reload(shared: &SharedConfig) {
+    let current = shared.load_full();
+
+    let config = match current.try_read() {
+        Ok(config) => config,
+        Err(err) => return log::error!("Keeping the running config, {} is invalid: {err}", current.config_path),
+    };
+
+    let changes = diff(&current, &config);
+    if changes.is_empty() {
+        return log::debug!("Config {} reloaded without changes", current.config_path);
+    }
+
+    for change in &changes {
+        log::info!("Config changed {change}");
+    }
+
+    if changes.iter().any(|change| change.starts_with("server.") && !RELOADABLE.iter().any(|path| change.starts_with(&format!("{path}:")))) {
+        log::warn!("Changes to [server] only take effect after a restart");
+    }
+
+    shared.store(Arc::new(config));
+}
+
+/// Reload the config on SIGHUP or when the file changes on disk
+pub fn watch(shared: SharedConfig) {
+    let path = shared.load().config_path.clone();
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(signal) => signal,
+        Err(err) => return log::error!("Cannot listen for SIGHUP, config reloading is disabled: {err}"),
+    };
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        let mut last = modified(&path);
+
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => log::info!("Received SIGHUP, reloading {path}"),
+                _ = interval.tick() => match modified(&path) {
+                    time if time == last => continue,
+                    _ => log::info!("{path} changed on disk, reloading"),
+                },
+            }
+
+            last = modified(&path);
+            reload(&shared);
+        }
+    });
+}
@@ -0,0 +1,246 @@
+use crate::{
+    config::{SharedConfig, Webhook},
+    http::{Domain, Record},
+    store::DomainStore,
+    time,
+};
+use actix_web::web::Bytes;
+use anyhow::Error;
+use futures::stream::{self, Stream};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+use tokio::sync::broadcast::{self, error::RecvError, Receiver, Sender};
+
+/// Events kept in memory for subscribers that fall behind, older ones are read back from the store
+const BUFFER: usize = 1024;
+
+/// Delays before retrying a failed webhook delivery
+const RETRIES: [Duration; 3] = [Duration::from_secs(5), Duration::from_secs(30), Duration::from_secs(300)];
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Comment sent to idle streams so proxies keep the connection open
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Events read from the store at once when replaying
+const REPLAY_PAGE: u64 = 100;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Created,
+    Updated,
+    Deleted,
+    Suspended,
+    Reinstated,
+}
+
+/// A change to the registry, numbered in the order it happened
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Event {
+    pub(crate) id: u64,
+    pub(crate) event: EventKind,
+    pub(crate) name: String,
+    pub(crate) tld: String,
+    pub(crate) at: u64,
+    /// Target and records after the change, empty for deletions
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub(crate) ip: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) records: Vec<Record>,
+}
+
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::Created => "created",
+            EventKind::Updated => "updated",
+            EventKind::Deleted => "deleted",
+            EventKind::Suspended => "suspended",
+            EventKind::Reinstated => "reinstated",
+        }
+    }
+}
+
+/// Records registry changes in the event log and hands them to subscribers
+pub(crate) struct Events {
+    db: Arc<dyn DomainStore>,
+    sender: Sender<Event>,
+}
+
+impl Events {
+    pub fn new(db: Arc<dyn DomainStore>) -> Arc<Self> { Arc::new(Events { db, sender: broadcast::channel(BUFFER).0 }) }
+
+    pub fn subscribe(&self) -> Receiver<Event> { self.sender.subscribe() }
+
+    /// Append an event about `domain`, failures are logged as the change itself already happened
+    pub async fn emit(&self, kind: EventKind, domain: &Domain) {
+        let (ip, records) = match kind {
+            EventKind::Deleted => (String::new(), Vec::new()),
+            _ => (domain.ip.clone(), domain.records()),
+        };
+
+        let event = Event {
+            id: 0,
+            name: domain.name.clone(),
+            tld: domain.tld.clone(),
+            at: time::now(),
+            event: kind,
+            ip,
+            records,
+        };
+
+        match self.db.append_event(event).await {
+            // no receivers is not an error, nobody is listening yet
+            Ok(event) => drop(self.sender.send(event)),
+            Err(err) => log::error!("Failed to record {} event for {}.{}: {err}", kind.name(), domain.name, domain.tld),
+        }
+    }
+}
+
+/// Server-sent event frame of `event`
+fn frame(event: &Event) -> Option<Bytes> {
+    let data = serde_json::to_string(event).ok()?;
+    Some(Bytes::from(format!("id: {}\nevent: {}\ndata: {data}\n\n", event.id, event.event.name())))
+}
+
+struct Replay {
+    events: Arc<Events>,
+    receiver: Receiver<Event>,
+    backlog: VecDeque<Event>,
+    /// Id of the last event sent, `None` until one is when only following live events
+    last: Option<u64>,
+    caught_up: bool,
+}
+
+/// Server-sent events after `after` followed by live ones, or only live ones without it.
+/// Subscribing comes first so nothing is missed between the replay and the live events.
+pub(crate) fn stream(events: Arc<Events>, after: Option<u64>) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    let replay = Replay {
+        receiver: events.subscribe(),
+        backlog: VecDeque::new(),
+        caught_up: after.is_none(),
+        last: after,
+        events,
+    };
+
+    stream::unfold(replay, |mut replay| async move {
+        loop {
+            if let Some(event) = replay.backlog.pop_front() {
+                if replay.last.is_some_and(|last| event.id <= last) {
+                    continue;
+                }
+
+                replay.last = Some(event.id);
+                match frame(&event) {
+                    Some(frame) => return Some((Ok(frame), replay)),
+                    None => continue,
+                }
+            }
+
+            if !replay.caught_up {
+                let after = replay.last.unwrap_or_default();
+
+                match replay.events.db.events_after(after, REPLAY_PAGE).await {
+                    Ok(page) => {
+                        replay.caught_up = (page.len() as u64) < REPLAY_PAGE;
+                        replay.backlog.extend(page);
+                        continue;
+                    }
+                    Err(err) => {
+                        log::error!("Failed to replay events after {after}: {err}");
+                        return None;
+                    }
+                }
+            }
+
+            match tokio::time::timeout(KEEPALIVE, replay.receiver.recv()).await {
+                Err(_) => return Some((Ok(Bytes::from_static(b": keepalive\n\n")), replay)),
+                Ok(Ok(event)) => match replay.last {
+                    Some(last) if event.id <= last => continue,
+                    // ids are handed out before broadcasting, so concurrent changes can arrive out of order
+                    Some(last) if event.id > last.saturating_add(1) => replay.caught_up = false,
+                    _ => replay.backlog.push_back(event),
+                },
+                // without a position there is nothing to replay from, carry on with live events
+                Ok(Err(RecvError::Lagged(_))) => replay.caught_up = replay.last.is_none(),
+                Ok(Err(RecvError::Closed)) => return None,
+            }
+        }
+    })
+}
+
+/// `sha256=` followed by the hex HMAC of `timestamp.body`
+pub fn signature(secret: &str, timestamp: u64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{timestamp}.").as_bytes());
+    mac.update(body);
+
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+async fn deliver(client: &reqwest::Client, hook: &Webhook, event: &Event, body: &[u8]) -> Result<(), Error> {
+    let timestamp = time::now();
+
+    client
+        .post(&hook.url)
+        .header("content-type", "application/json")
+        .header("x-webx-event", event.event.name())
+        .header("x-webx-delivery", event.id)
+        .header("x-webx-timestamp", timestamp)
+        .header("x-webx-signature", signature(&hook.secret, timestamp, body))
+        .body(body.to_vec())
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+/// Post every event to the webhooks subscribed to it, retrying failed deliveries in the background
+pub(crate) fn spawn_webhooks(config: SharedConfig, events: &Events) {
+    let mut receiver = events.subscribe();
+    let client = match reqwest::Client::builder().timeout(DELIVERY_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(err) => return log::error!("Cannot create webhook client, webhooks are disabled: {err}"),
+    };
+
+    tokio::spawn(async move {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    log::warn!("Webhooks fell behind and skipped {missed} events");
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+
+            let Ok(body) = serde_json::to_vec(&event) else { continue };
+            let hooks = config.load().settings.webhooks.clone();
+
+            for hook in hooks.into_iter().filter(|hook| hook.events.is_empty() || hook.events.contains(&event.event)) {
+                let (client, event, body) = (client.clone(), event.clone(), body.clone());
+
+                tokio::spawn(async move {
+                    let mut delays = RETRIES.iter();
+
+                    loop {
+                        match deliver(&client, &hook, &event, &body).await {
+                            Ok(_) => return,
+                            Err(err) => match delays.next() {
+                                Some(delay) => {
+                                    log::debug!("Webhook {} failed for event {}, retrying in {}s: {err}", hook.url, event.id, delay.as_secs());
+                                    tokio::time::sleep(*delay).await;
+                                }
+                                None => return log::warn!("Webhook {} gave up on event {}: {err}", hook.url, event.id),
+                            },
+                        }
+                    }
+                });
+            }
+        }
+    });
+}
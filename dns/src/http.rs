@@ -1,24 +1,42 @@
+mod admin;
+mod errors;
 mod helpers;
 mod models;
+mod moderation;
+mod owners;
+mod proxy;
 mod ratelimit;
 mod routes;
+mod status;
 
-use crate::config::Config;
-use actix_governor::{Governor, GovernorConfigBuilder};
-use actix_web::{http::Method, web, web::Data, App, HttpRequest, HttpServer};
+use crate::{
+    cache::Cache,
+    config::{self, Config, SharedConfig},
+    events::{self, Events},
+    nameserver::Nameserver,
+    reclaim,
+    store::DomainStore,
+};
+use actix_http::HttpService;
+use actix_service::{fn_service, map_config, ServiceFactoryExt};
+use actix_web::{dev::AppConfig, web, web::Data, App, HttpRequest, HttpServer};
 use anyhow::{anyhow, Error};
 use colored::Colorize;
 use macros_rs::fmt::{crashln, string};
-use ratelimit::RealIpKeyExtractor;
-use std::{net::IpAddr, str::FromStr, time::Duration};
+use ratelimit::Limiter;
+use std::sync::Arc;
 
-pub(crate) use models::Domain;
+pub(crate) use helpers::{check_records, check_target, is_offensive};
+pub(crate) use models::{target, Change, Cursor, Domain, Order, Owner, Record, RecordKind, Reservation, SortBy, Suspension, Verification};
 
 #[derive(Clone)]
 pub(crate) struct AppState {
-    trusted: IpAddr,
-    config: Config,
-    db: mongodb::Collection<Domain>,
+    config: SharedConfig,
+    keys: sled::Db,
+    db: Arc<dyn DomainStore>,
+    events: Arc<Events>,
+    cache: Arc<Cache>,
+    limiter: Arc<Limiter>,
 }
 
 pub fn get_token<'a>(req: &'a HttpRequest) -> Result<(&'a str, &'a str), Error> {
@@ -27,7 +45,7 @@ pub fn get_token<'a>(req: &'a HttpRequest) -> Result<(&'a str, &'a str), Error>
         None => return Err(anyhow!("Missing header authorization")),
     };
 
-    let chunks: Vec<&'a str> = header.split(":").collect();
+    let chunks: Vec<&'a str> = header.split(':').collect();
 
     if chunks.len() == 2 {
         Ok((chunks[0], chunks[1]))
@@ -36,48 +54,120 @@ pub fn get_token<'a>(req: &'a HttpRequest) -> Result<(&'a str, &'a str), Error>
     }
 }
 
+/// Every route, mounted under `/v1` and at the root for older clients
+fn api(cfg: &mut web::ServiceConfig) {
+    cfg.service(routes::index)
+        // ahead of `/domain/{name}/{tld}`, which would otherwise take `history` for a tld
+        .service(routes::key_history)
+        .service(routes::get_domain)
+        .service(routes::update_domain)
+        .service(routes::delete_domain)
+        .service(routes::rotate_key)
+        .service(routes::renew_domain)
+        .service(routes::start_verification)
+        .service(routes::check_verification)
+        .service(routes::update_subdomain)
+        .service(routes::delete_subdomain)
+        .service(routes::get_domains)
+        .service(routes::resolve_domains)
+        .service(routes::get_tlds)
+        .service(routes::event_stream)
+        .service(routes::check_domain)
+        .service(routes::elevated_domain)
+        .service(routes::elevated_get)
+        .service(routes::elevated_update)
+        .service(routes::elevated_delete)
+        .service(admin::list_keys)
+        .service(admin::create_key)
+        .service(admin::export_keys)
+        .service(admin::key_info)
+        .service(admin::delete_key)
+        .service(admin::reassign_domain)
+        .service(moderation::force_delete)
+        .service(moderation::domain_history)
+        .service(moderation::suspend_domain)
+        .service(moderation::reinstate_domain)
+        .service(moderation::list_reserved)
+        .service(moderation::reserve_name)
+        .service(moderation::release_name)
+        .service(moderation::release_tld)
+        .service(owners::owner_domains)
+        .service(owners::claim_domain)
+        .service(owners::rotate_owned)
+        .service(owners::owned_history)
+        .service(owners::transfer_domain)
+        .route("/domain", web::post().to(routes::create_domain))
+        .route("/owner", web::post().to(owners::create_owner));
+}
+
 #[actix_web::main]
 pub async fn start(cli: crate::Cli) -> std::io::Result<()> {
     let config = Config::new().set_path(&cli.config).read();
 
-    let trusted_ip = match IpAddr::from_str(&config.server.address) {
-        Ok(addr) => addr,
-        Err(err) => crashln!("Cannot parse address.\n{}", string!(err).white()),
+    let db = match config.connect_store().await {
+        Ok(store) => store,
+        Err(err) => crashln!("Failed to connect to storage backend.\n{}", string!(err).white()),
     };
 
-    let governor_builder = GovernorConfigBuilder::default()
-        .methods(vec![Method::POST])
-        .period(Duration::from_secs(600))
-        .burst_size(5)
-        .key_extractor(RealIpKeyExtractor)
-        .finish()
-        .unwrap();
-
-    let db = match config.connect_to_mongo().await {
-        Ok(client) => client,
-        Err(err) => crashln!("Failed to connect to MongoDB.\n{}", string!(err).white()),
+    let keys = match crate::kv::open(&config.server.key_db) {
+        Ok(db) => db,
+        Err(err) => crashln!("Failed to open key database.\n{}", string!(err).white()),
     };
 
+    let shared = config.clone().shared();
+    config::watch(shared.clone());
+    let events = Events::new(db.clone());
+    let cache = Arc::new(Cache::new(&config));
+    events::spawn_webhooks(shared.clone(), &events);
+    reclaim::spawn(shared.clone(), db.clone(), events.clone(), cache.clone());
+
+    if config.server.dns.enabled {
+        if let Err(err) = Nameserver::new(shared.clone(), db.clone()).start(&config).await {
+            crashln!("Failed to start DNS listener.\n{}", string!(err).white());
+        }
+    }
+
+    let limiter = Arc::new(Limiter::default());
+
+    let app_config = shared.clone();
     let app = move || {
         let data = AppState {
             db: db.clone(),
-            trusted: trusted_ip,
-            config: Config::new().set_path(&cli.config).read(),
+            keys: keys.clone(),
+            events: events.clone(),
+            cache: cache.clone(),
+            config: app_config.clone(),
+            limiter: limiter.clone(),
         };
 
         App::new()
             .app_data(Data::new(data))
-            .service(routes::index)
-            .service(routes::get_domain)
-            .service(routes::update_domain)
-            .service(routes::delete_domain)
-            .service(routes::get_domains)
-            .service(routes::get_tlds)
-            .service(routes::check_domain)
-            .service(routes::elevated_domain)
-            .route("/domain", web::post().to(routes::create_domain).wrap(Governor::new(&governor_builder)))
+            .wrap(ratelimit::RateLimiter)
+            .wrap_fn(status::track)
+            .configure(errors::extractors)
+            .service(status::metrics)
+            .service(status::healthz)
+            .service(status::readyz)
+            .service(web::scope("/v1").configure(api))
+            .configure(api)
+            .default_service(web::to(errors::unknown_route))
     };
 
     log::info!("Listening on {}", config.get_address());
-    HttpServer::new(app).bind(config.get_address())?.run().await
+
+    if !config.server.proxy.protocol {
+        return HttpServer::new(app).bind(config.get_address())?.run().await;
+    }
+
+    // the PROXY header has to be read before the HTTP service sees the connection
+    actix_server::Server::build()
+        .bind("webx_dns", config.get_address(), move || {
+            let config = shared.clone();
+
+            fn_service(move |stream| proxy::accept(stream, config.clone()))
+                .map_err(|err| log::warn!("{err}"))
+                .and_then(HttpService::build().finish(map_config(app(), |_| AppConfig::default())).map_err(|err| log::debug!("{err:?}")))
+        })?
+        .run()
+        .await
 }
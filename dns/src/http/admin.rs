@@ -0,0 +1,134 @@
+use super::{
+    errors::{self, Code},
+    helpers,
+    models::*,
+    AppState,
+};
+use crate::{
+    kv::{self, Scope},
+    secret,
+};
+
+use actix_web::{
+    web::{self, Data},
+    HttpRequest, HttpResponse, Responder,
+};
+
+#[actix_web::get("/admin/keys")]
+pub(crate) async fn list_keys(app: Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(error) = helpers::authorize_key(&req, &app, Scope::Admin).await {
+        return error;
+    }
+
+    match kv::all(&app.keys) {
+        Ok(keys) => HttpResponse::Ok().json(keys.into_iter().map(|(name, info)| KeyInfo { name, key: None, info }).collect::<Vec<_>>()),
+        Err(err) => errors::internal(err),
+    }
+}
+
+#[actix_web::get("/admin/keys/{name}")]
+pub(crate) async fn key_info(path: web::Path<String>, app: Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(error) = helpers::authorize_key(&req, &app, Scope::Admin).await {
+        return error;
+    }
+
+    let name = path.into_inner();
+
+    match kv::get(&app.keys, &name) {
+        Ok(info) => HttpResponse::Ok().json(KeyInfo {
+            name,
+            key: None,
+            info: kv::ApiKey { hash: String::new(), ..info },
+        }),
+        Err(_) => errors::error(Code::KeyNotFound, format!("No key named {name}.")),
+    }
+}
+
+#[actix_web::post("/admin/keys")]
+pub(crate) async fn create_key(body: web::Json<NewKey>, app: Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(error) = helpers::authorize_key(&req, &app, Scope::Admin).await {
+        return error;
+    }
+
+    let NewKey { name, scopes, expires, description } = body.into_inner();
+
+    if name.is_empty() || name.contains(':') {
+        return errors::failed(Code::InvalidKeyName, "Failed to create key", "Key names can not be empty or contain ':'.");
+    }
+
+    if kv::get(&app.keys, &name).is_ok() {
+        return errors::failed(Code::KeyTaken, "Failed to create key", format!("A key named {name} already exists."));
+    }
+
+    if expires.is_some_and(|days| kv::expiry_after(days).is_none()) {
+        return errors::failed(Code::InvalidExpiry, "Failed to create key", "The expiry is too far in the future.");
+    }
+
+    match kv::create(&app.keys, &name, scopes, expires, description) {
+        Ok((key, info)) => HttpResponse::Ok().json(KeyInfo {
+            name,
+            key: Some(key),
+            info: kv::ApiKey { hash: String::new(), ..info },
+        }),
+        Err(err) => errors::internal(err),
+    }
+}
+
+#[actix_web::delete("/admin/keys/{name}")]
+pub(crate) async fn delete_key(path: web::Path<String>, app: Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(error) = helpers::authorize_key(&req, &app, Scope::Admin).await {
+        return error;
+    }
+
+    let name = path.into_inner();
+
+    match kv::remove(&app.keys, &name) {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => errors::error(Code::KeyNotFound, format!("No key named {name}.")),
+        Err(err) => errors::internal(err),
+    }
+}
+
+#[actix_web::get("/admin/export")]
+pub(crate) async fn export_keys(app: Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(error) = helpers::authorize_key(&req, &app, Scope::Admin).await {
+        return error;
+    }
+
+    let mut out = Vec::new();
+
+    match kv::save(&app.keys, &mut out) {
+        Ok(_) => HttpResponse::Ok().content_type("text/csv").body(out),
+        Err(err) => errors::internal(err),
+    }
+}
+
+#[actix_web::post("/admin/domain/{name}/{tld}/reassign")]
+pub(crate) async fn reassign_domain(path: web::Path<(String, String)>, body: web::Json<Transfer>, app: Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(error) = helpers::authorize_key(&req, &app, Scope::Admin).await {
+        return error;
+    }
+
+    let (name, tld) = path.into_inner();
+    let owner = body.into_inner().owner;
+
+    let key = match helpers::domain_key(&name, &tld, &app).await {
+        Ok(key) => key,
+        Err(error) => return error,
+    };
+
+    match app.db.find_owner(&owner).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return errors::failed(Code::OwnerNotFound, "Failed to reassign domain", format!("Owner {owner} does not exist")),
+        Err(err) => return errors::internal(err),
+    }
+
+    // like a transfer between owners the previous key stops working, the new key is only returned here
+    let secret_key = secret::generate(31);
+
+    match app.db.transfer(&key, &owner, &secret::digest(&secret_key)).await {
+        Ok(true) => HttpResponse::Ok().json(SecretKey { secret_key }),
+        Ok(false) => errors::domain_not_found(),
+        Err(err) => errors::internal(err),
+    }
+}
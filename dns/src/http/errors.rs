@@ -0,0 +1,177 @@
+use super::models::Error;
+use actix_web::{
+    error::{self, JsonPayloadError, PathError, QueryPayloadError},
+    http::StatusCode,
+    web, HttpRequest, HttpResponse,
+};
+use serde::{Serialize, Serializer};
+use std::fmt::Display;
+
+/// Why a request failed. The serialized codes are part of the API and never change meaning.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Code {
+    InvalidBody,
+    InvalidQuery,
+    InvalidPath,
+    RouteNotFound,
+    Unauthorized,
+    KeyExpired,
+    MissingScope,
+    NotOwner,
+    DomainNotFound,
+    RecordNotFound,
+    OwnerNotFound,
+    KeyNotFound,
+    ReservationNotFound,
+    DomainTaken,
+    OwnerTaken,
+    KeyTaken,
+    InvalidTarget,
+    InvalidRecord,
+    InvalidKeyName,
+    InvalidOwnerName,
+    InvalidPagination,
+    InvalidExpiry,
+    BatchTooLarge,
+    MissingReason,
+    UnknownTld,
+    /// A naming rule, see `naming::Violation::code`
+    Naming(&'static str),
+    Offensive,
+    Reserved,
+    RegistrationInvite,
+    RegistrationClosed,
+    OwnerRequired,
+    QuotaExceeded,
+    DomainSuspended,
+    DomainLocked,
+    DomainExpired,
+    NotRenewable,
+    AlreadyRenewed,
+    NotVerifiable,
+    VerificationMissing,
+    VerificationFailed,
+    Ratelimited,
+    Internal,
+}
+
+impl Code {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Code::InvalidBody => "invalid_body",
+            Code::InvalidQuery => "invalid_query",
+            Code::InvalidPath => "invalid_path",
+            Code::RouteNotFound => "route_not_found",
+            Code::Unauthorized => "unauthorized",
+            Code::KeyExpired => "key_expired",
+            Code::MissingScope => "missing_scope",
+            Code::NotOwner => "not_owner",
+            Code::DomainNotFound => "domain_not_found",
+            Code::RecordNotFound => "record_not_found",
+            Code::OwnerNotFound => "owner_not_found",
+            Code::KeyNotFound => "key_not_found",
+            Code::ReservationNotFound => "reservation_not_found",
+            Code::DomainTaken => "domain_taken",
+            Code::OwnerTaken => "owner_taken",
+            Code::KeyTaken => "key_taken",
+            Code::InvalidTarget => "invalid_target",
+            Code::InvalidRecord => "invalid_record",
+            Code::InvalidKeyName => "invalid_key_name",
+            Code::InvalidOwnerName => "invalid_owner_name",
+            Code::InvalidPagination => "invalid_pagination",
+            Code::InvalidExpiry => "invalid_expiry",
+            Code::BatchTooLarge => "batch_too_large",
+            Code::MissingReason => "missing_reason",
+            Code::UnknownTld => "unknown_tld",
+            Code::Naming(code) => code,
+            Code::Offensive => "offensive",
+            Code::Reserved => "reserved",
+            Code::RegistrationInvite => "registration_invite",
+            Code::RegistrationClosed => "registration_closed",
+            Code::OwnerRequired => "owner_required",
+            Code::QuotaExceeded => "quota_exceeded",
+            Code::DomainSuspended => "domain_suspended",
+            Code::DomainLocked => "domain_locked",
+            Code::DomainExpired => "domain_expired",
+            Code::NotRenewable => "not_renewable",
+            Code::AlreadyRenewed => "already_renewed",
+            Code::NotVerifiable => "not_verifiable",
+            Code::VerificationMissing => "verification_missing",
+            Code::VerificationFailed => "verification_failed",
+            Code::Ratelimited => "ratelimited_endpoint",
+            Code::Internal => "internal_error",
+        }
+    }
+
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            Code::Unauthorized | Code::KeyExpired => StatusCode::UNAUTHORIZED,
+            Code::MissingScope | Code::NotOwner | Code::DomainLocked => StatusCode::FORBIDDEN,
+            Code::Reserved | Code::RegistrationInvite | Code::RegistrationClosed | Code::OwnerRequired | Code::QuotaExceeded => StatusCode::FORBIDDEN,
+            Code::RouteNotFound | Code::DomainNotFound | Code::RecordNotFound | Code::OwnerNotFound | Code::KeyNotFound | Code::ReservationNotFound => StatusCode::NOT_FOUND,
+            Code::DomainTaken | Code::OwnerTaken | Code::KeyTaken | Code::AlreadyRenewed => StatusCode::CONFLICT,
+            Code::DomainSuspended => StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
+            Code::DomainExpired => StatusCode::GONE,
+            Code::Ratelimited => StatusCode::TOO_MANY_REQUESTS,
+            Code::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    /// Summary used as `msg` when a handler does not name what failed
+    fn summary(&self) -> &'static str {
+        match self.status() {
+            StatusCode::UNAUTHORIZED => "Unauthorized",
+            StatusCode::FORBIDDEN => "Forbidden",
+            StatusCode::NOT_FOUND => "Not found",
+            StatusCode::CONFLICT => "Conflict",
+            StatusCode::INTERNAL_SERVER_ERROR => "Internal server error",
+            _ => "Invalid request",
+        }
+    }
+}
+
+impl Serialize for Code {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> { serializer.serialize_str(self.as_str()) }
+}
+
+/// Error response for `code`, `error` explains it
+pub(crate) fn error(code: Code, error: impl Into<String>) -> HttpResponse { failed(code, code.summary(), error) }
+
+/// Error response for `code`, `msg` names what failed
+pub(crate) fn failed(code: Code, msg: &'static str, error: impl Into<String>) -> HttpResponse { detailed(code, msg, error, None::<()>) }
+
+/// Error response carrying extra fields next to the usual ones
+pub(crate) fn detailed<T: Serialize>(code: Code, msg: &'static str, error: impl Into<String>, details: Option<T>) -> HttpResponse {
+    let mut response = HttpResponse::build(code.status()).json(Error {
+        code,
+        msg,
+        error: error.into(),
+        details,
+    });
+
+    // lets callers tell failures apart without parsing the body
+    response.extensions_mut().insert(code);
+    response
+}
+
+/// Log a failure of the storage backend or key database, clients only learn that it happened
+pub(crate) fn internal(err: impl Display) -> HttpResponse {
+    log::error!("Request failed: {err}");
+    error(Code::Internal, "Something went wrong, try again later.")
+}
+
+/// The most common failure, no domain matches the name or key given
+pub(crate) fn domain_not_found() -> HttpResponse { error(Code::DomainNotFound, "The domain does not exist.") }
+
+/// Answer unknown routes with an error body instead of an empty 404
+pub(crate) async fn unknown_route(req: HttpRequest) -> HttpResponse { error(Code::RouteNotFound, format!("No route for {} {}.", req.method(), req.path())) }
+
+/// Extractor configs answering malformed bodies, queries and paths in the error format
+pub(crate) fn extractors(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(web::JsonConfig::default().error_handler(|err: JsonPayloadError, _| rejected(Code::InvalidBody, err)))
+        .app_data(web::QueryConfig::default().error_handler(|err: QueryPayloadError, _| rejected(Code::InvalidQuery, err)))
+        .app_data(web::PathConfig::default().error_handler(|err: PathError, _| rejected(Code::InvalidPath, err)));
+}
+
+fn rejected(code: Code, err: impl Display) -> actix_web::Error { error::InternalError::from_response(err.to_string(), error(code, err.to_string())).into() }
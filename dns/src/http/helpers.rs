@@ -1,30 +1,343 @@
-use super::{models::*, AppState};
-use actix_web::{web::Data, HttpResponse};
-use mongodb::bson::doc;
+use super::{
+    errors::{self, Code},
+    models::*,
+    AppState,
+};
+use crate::{
+    config::{Config, Registration},
+    events::EventKind,
+    kv::{self, ApiKey, Scope},
+    naming,
+};
+use actix_web::{
+    http::header::{CacheControl, CacheDirective, ContentType, ETag, EntityTag, IfNoneMatch},
+    web, HttpMessage, HttpRequest, HttpResponse,
+};
 use regex::Regex;
-use serde::Deserialize;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::LazyLock,
+};
 
-pub fn validate_ip(domain: &Domain) -> Result<(), HttpResponse> {
-    let valid_url = Regex::new(r"(?i)\bhttps?://[-a-z0-9+&@#/%?=~_|!:,.;]*[-a-z0-9+&@#/%=~_|]").unwrap();
+/// Alternatives suggested for a taken name
+const MAX_SUGGESTIONS: usize = 10;
+
+/// An http(s) URL anywhere in the legacy `ip` target
+static TARGET_URL: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?i)\bhttps?://[-a-z0-9+&@#/%?=~_|!:,.;]*[-a-z0-9+&@#/%=~_|]").unwrap());
+
+/// The whole value of a URL record
+static RECORD_URL: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?i)^https?://[-a-z0-9+&@#/%?=~_|!:,.;]*[-a-z0-9+&@#/%=~_|]$").unwrap());
 
+/// `@` or dot separated subdomain labels
+static RECORD_NAME: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^(@|[a-z0-9]([a-z0-9-]{0,61}[a-z0-9])?(\.[a-z0-9]([a-z0-9-]{0,61}[a-z0-9])?)*)$").unwrap());
+
+pub fn validate_ip(domain: &Domain) -> Result<(), HttpResponse> {
     let is_valid_ip = domain.ip.parse::<Ipv4Addr>().is_ok() || domain.ip.parse::<Ipv6Addr>().is_ok();
-    let is_valid_url = valid_url.is_match(&domain.ip);
-
-    if is_valid_ip || is_valid_url {
-        if domain.name.len() <= 100 {
-            Ok(())
-        } else {
-            Err(HttpResponse::BadRequest().json(Error {
-                msg: "Failed to create domain",
-                error: "Invalid name, non-existent TLD, or name too long (100 chars).".into(),
-            }))
+    let is_valid_url = TARGET_URL.is_match(&domain.ip);
+
+    match is_valid_ip || is_valid_url {
+        true => Ok(()),
+        false => Err(errors::failed(
+            Code::InvalidTarget,
+            "Failed to create domain",
+            format!("Invalid ip '{}', expected an IP address or an http(s) URL.", domain.ip),
+        )),
+    }
+}
+
+const MAX_RECORDS: usize = 32;
+const MAX_TXT_LEN: usize = 1024;
+
+fn record_error(error: String) -> HttpResponse { errors::failed(Code::InvalidRecord, "Invalid record", error) }
+
+fn check_record(record: &Record) -> Result<(), String> {
+    if !RECORD_NAME.is_match(&record.name) {
+        return Err(format!("Invalid record name '{}', expected '@' or a subdomain label.", record.name));
+    }
+
+    if let Some(ttl) = record.ttl {
+        if !(60..=86400).contains(&ttl) {
+            return Err(format!("Invalid ttl {ttl}, must be between 60 and 86400 seconds."));
+        }
+    }
+
+    let valid = match record.kind {
+        RecordKind::A => record.value.parse::<Ipv4Addr>().is_ok(),
+        RecordKind::Aaaa => record.value.parse::<Ipv6Addr>().is_ok(),
+        RecordKind::Url => RECORD_URL.is_match(&record.value),
+        RecordKind::Txt => record.value.len() <= MAX_TXT_LEN && !record.value.chars().any(char::is_control),
+    };
+
+    match valid {
+        true => Ok(()),
+        false => Err(format!("Invalid value '{}' for {} record.", record.value, format!("{:?}", record.kind).to_uppercase())),
+    }
+}
+
+/// Check the legacy `ip` target as the record kind it looks like
+pub(crate) fn check_target(ip: &str) -> Result<(), String> {
+    check_record(&Record {
+        kind: RecordKind::infer(ip),
+        name: "@".into(),
+        value: ip.to_string(),
+        ttl: None,
+    })
+}
+
+/// Validate the legacy `ip` target as the record kind it looks like
+pub fn validate_target(ip: &str) -> Result<(), HttpResponse> { check_target(ip).map_err(record_error) }
+
+/// Validate a record set and reconcile it with the legacy `ip` field,
+/// returning the primary target and the records to store.
+pub fn validate_records(ip: &str, records: &[Record]) -> Result<(String, Vec<Record>), HttpResponse> { check_records(ip, records).map_err(record_error) }
+
+/// What `validate_records` checks, with the reason a record set is refused
+pub(crate) fn check_records(ip: &str, records: &[Record]) -> Result<(String, Vec<Record>), String> {
+    if records.is_empty() {
+        return Ok((ip.to_string(), Vec::new()));
+    }
+
+    for record in records {
+        check_record(record)?;
+    }
+
+    let mut records = records.to_vec();
+
+    match records.iter().find(|record| record.is_apex() && record.kind.is_target()) {
+        Some(target) if ip.is_empty() || target.value == ip => {}
+        Some(target) => return Err(format!("ip '{ip}' does not match the target '{}' on '@'.", target.value)),
+        None if ip.is_empty() => return Err("Records need at least one A, AAAA or URL record on '@'.".into()),
+        // `ip` becomes the target on '@', so lookups through the records see it too
+        None => {
+            check_target(ip)?;
+            records.insert(
+                0,
+                Record {
+                    kind: RecordKind::infer(ip),
+                    name: "@".into(),
+                    value: ip.to_string(),
+                    ttl: None,
+                },
+            );
         }
-    } else {
-        Err(HttpResponse::BadRequest().json(Error {
-            msg: "Failed to create domain",
-            error: "Invalid name, non-existent TLD, or name too long (100 chars).".into(),
-        }))
+    }
+
+    if records.len() > MAX_RECORDS {
+        return Err(format!("Too many records, a domain can hold at most {MAX_RECORDS}."));
+    }
+
+    let target = records.iter().find(|record| record.is_apex() && record.kind.is_target()).map(|record| record.value.clone()).unwrap_or_default();
+    Ok((target, records))
+}
+
+/// Records to store for an update of `domain`, sending only `ip` replaces the target on '@' and keeps every other record
+pub fn update_records(domain: &Domain, ip: &str, records: Vec<Record>) -> Vec<Record> {
+    if !records.is_empty() || ip.is_empty() {
+        return records;
+    }
+
+    // domains without records of their own stay on the legacy `ip`
+    domain.records.iter().filter(|record| !(record.is_apex() && record.kind.is_target())).cloned().collect()
+}
+
+/// Build the records of subdomain `sub` from an update body
+pub fn validate_subdomain(sub: &str, update: UpdateDomain) -> Result<Vec<Record>, HttpResponse> {
+    let records = match update.records.is_empty() {
+        true if update.ip.is_empty() => return Err(record_error("Subdomains need an ip or at least one record.".into())),
+        true => vec![Record {
+            kind: RecordKind::infer(&update.ip),
+            name: sub.to_string(),
+            value: update.ip,
+            ttl: None,
+        }],
+        false => update.records.into_iter().map(|record| Record { name: sub.to_string(), ..record }).collect(),
+    };
+
+    if sub == "@" {
+        return Err(record_error("Subdomain name can not be '@'.".into()));
+    }
+
+    for record in &records {
+        check_record(record).map_err(record_error)?;
+    }
+
+    Ok(records)
+}
+
+/// Authenticate an owner account from the `authorization: name:token` header
+pub async fn authorize_owner(req: &HttpRequest, app: &AppState) -> Result<Owner, HttpResponse> {
+    let (name, token) = super::get_token(req).map_err(|err| errors::failed(Code::Unauthorized, "Authorization failed", err.to_string()))?;
+
+    let owner = match app.db.find_owner(name).await {
+        Ok(Some(owner)) => owner,
+        Ok(None) => return Err(errors::failed(Code::Unauthorized, "Invalid authorization header", "Token is invalid")),
+        Err(err) => return Err(errors::internal(err)),
+    };
+
+    // argon2 is slow on purpose, keep it off the async workers
+    let (token, hash) = (token.to_string(), owner.token.clone());
+    match web::block(move || crate::secret::verify(&token, &hash)).await {
+        Ok(true) => Ok(owner),
+        Ok(false) => Err(errors::failed(Code::Unauthorized, "Invalid authorization header", "Token is invalid")),
+        Err(err) => Err(errors::internal(err)),
+    }
+}
+
+/// Outcome of checking the API key of a request, kept in its extensions so the secret is only hashed once
+#[derive(Clone)]
+pub(crate) struct CheckedKey(Option<ApiKey>);
+
+/// Check the `authorization: name:key` header against the key database, `None` when the secret does not match
+pub(crate) async fn check_key(req: &HttpRequest, app: &AppState) -> Result<Option<ApiKey>, HttpResponse> {
+    if let Some(CheckedKey(key)) = req.extensions().get::<CheckedKey>() {
+        return Ok(key.clone());
+    }
+
+    let (name, secret) = super::get_token(req).map_err(|err| errors::failed(Code::Unauthorized, "Authorization failed", err.to_string()))?;
+
+    // argon2 is slow on purpose, keep it off the async workers
+    let (keys, name, secret) = (app.keys.clone(), name.to_string(), secret.to_string());
+    let key = match web::block(move || kv::authorize(&keys, &name, &secret)).await {
+        Ok(Ok(key)) => key,
+        Ok(Err(err)) => return Err(errors::internal(err)),
+        Err(err) => return Err(errors::internal(err)),
+    };
+
+    req.extensions_mut().insert(CheckedKey(key.clone()));
+    Ok(key)
+}
+
+/// Authenticate a privileged API key from the `authorization: name:key` header
+/// and make sure it is allowed to act within `scope`
+pub async fn authorize_key(req: &HttpRequest, app: &AppState, scope: Scope) -> Result<ApiKey, HttpResponse> {
+    let Some(key) = check_key(req, app).await? else {
+        return Err(errors::failed(Code::Unauthorized, "Invalid authorization header", "Token is invalid"));
+    };
+    let name = super::get_token(req).map(|(name, _)| name).unwrap_or_default();
+
+    if key.is_expired() {
+        return Err(errors::failed(Code::KeyExpired, "Invalid authorization header", format!("Key {name} has expired")));
+    }
+
+    match key.allows(scope) {
+        true => Ok(key),
+        false => Err(errors::failed(Code::MissingScope, "Insufficient permissions", format!("Key {name} lacks the '{}' scope", scope.name()))),
+    }
+}
+
+/// Answer with `value` tagged by its hash, or `304 Not Modified` when `If-None-Match` names the same version.
+/// Clients and proxies may reuse the response for `max_age` seconds
+pub fn conditional<T: Serialize>(req: &HttpRequest, value: &T, max_age: u64) -> HttpResponse {
+    let body = match serde_json::to_vec(value) {
+        Ok(body) => body,
+        Err(err) => return errors::internal(err),
+    };
+
+    let etag = EntityTag::new_strong(hex::encode(&Sha256::digest(&body)[..16]));
+    let unchanged = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => false,
+    };
+
+    let mut response = match unchanged {
+        true => HttpResponse::NotModified(),
+        false => HttpResponse::Ok(),
+    };
+
+    response
+        .insert_header(ETag(etag))
+        .insert_header(CacheControl(vec![CacheDirective::Public, CacheDirective::MaxAge(max_age.min(u32::MAX as u64) as u32)]));
+
+    match unchanged {
+        true => response.finish(),
+        false => response.content_type(ContentType::json()).body(body),
+    }
+}
+
+/// Drop the cached lookup of `domain` and tell event subscribers about the change
+pub async fn changed(kind: EventKind, domain: &Domain, app: &AppState) {
+    app.cache.forget(&domain.name, &domain.tld);
+    app.events.emit(kind, domain).await;
+}
+
+/// Tell event subscribers about a change to the domain owning `key`
+pub async fn emit(kind: EventKind, key: &str, app: &AppState) {
+    match app.db.find_by_key(key).await {
+        Ok(Some(domain)) => changed(kind, &domain, app).await,
+        Ok(None) => {}
+        Err(err) => log::error!("Failed to fetch domain for {} event: {err}", kind.name()),
+    }
+}
+
+/// Delete the domain owning `key`, telling event subscribers
+pub async fn delete_domain(key: &str, app: &AppState) -> Result<bool, anyhow::Error> {
+    let Some(domain) = app.db.find_by_key(key).await? else { return Ok(false) };
+    let deleted = app.db.delete(key).await?;
+
+    if deleted {
+        changed(EventKind::Deleted, &domain, app).await;
+    }
+
+    Ok(deleted)
+}
+
+/// Name of the API key making the request, only call once the request is authorized
+pub fn key_name(req: &HttpRequest) -> String { super::get_token(req).map(|(name, _)| name.to_string()).unwrap_or_default() }
+
+/// Timestamps and ip changes of a domain, `full` includes changes made before it was last registered
+pub async fn domain_history(domain: Domain, full: bool, app: &AppState) -> HttpResponse {
+    match app.db.history(&domain.name, &domain.tld).await {
+        Ok(changes) => HttpResponse::Ok().json(DomainHistory {
+            domain: format!("{}.{}", domain.name, domain.tld),
+            changes: changes.into_iter().filter(|change| full || change.at >= domain.created).collect(),
+            created: domain.created,
+            updated: domain.updated,
+        }),
+        Err(err) => errors::internal(err),
+    }
+}
+
+/// Find a domain by name and hand back its stored key
+pub async fn domain_key(name: &str, tld: &str, app: &AppState) -> Result<String, HttpResponse> {
+    match app.db.find(&naming::to_ascii(name), tld).await {
+        Ok(Some(domain)) => Ok(domain.secret_key.unwrap_or_default()),
+        Ok(None) => Err(errors::domain_not_found()),
+        Err(err) => Err(errors::internal(err)),
+    }
+}
+
+/// Refuse changes by the holder of a suspended domain, so moderation can not be undone by deleting and registering it again
+pub fn unlocked(domain: &Domain) -> Result<(), HttpResponse> {
+    match &domain.suspended {
+        Some(suspension) => Err(errors::failed(
+            Code::DomainLocked,
+            "Domain is suspended",
+            format!("{}.{} was suspended ({}), it can not be changed until it is reinstated.", domain.name, domain.tld, suspension.reason),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Find the domain owning the digested secret `key` for a change by its holder
+pub async fn holder_domain(key: &str, app: &AppState) -> Result<Domain, HttpResponse> {
+    match app.db.find_by_key(key).await {
+        Ok(Some(domain)) => unlocked(&domain).map(|_| domain),
+        Ok(None) => Err(errors::domain_not_found()),
+        Err(err) => Err(errors::internal(err)),
+    }
+}
+
+/// Find a domain by name and make sure it belongs to `owner`
+pub async fn owned_domain(name: &str, tld: &str, owner: &Owner, app: &AppState) -> Result<Domain, HttpResponse> {
+    match app.db.find(&naming::to_ascii(name), tld).await {
+        Ok(Some(domain)) if domain.owner.as_deref() == Some(owner.name.as_str()) => Ok(domain),
+        Ok(Some(_)) => Err(errors::failed(Code::NotOwner, "Domain is not held by this account", format!("{name}.{tld} belongs to another owner"))),
+        Ok(None) => Err(errors::domain_not_found()),
+        Err(err) => Err(errors::internal(err)),
     }
 }
 
@@ -36,26 +349,199 @@ where
     Ok(s.to_lowercase())
 }
 
-pub async fn is_domain_taken(name: &str, tld: Option<&str>, app: Data<AppState>) -> Vec<DomainList> {
-    if let Some(tld) = tld {
-        let filter = doc! { "name": name, "tld": tld };
-        let taken = app.db.find_one(filter, None).await.unwrap().is_some();
+/// Whether the policy of `tld` reserves `name`
+fn reserved_by_policy(name: &str, tld: &str, app: &AppState) -> bool {
+    app.config.load().tld(tld).is_some_and(|policy| policy.reserves(name))
+}
+
+/// Whether `name` is reserved on `tld`, by the tld policy or by a moderator
+pub async fn is_reserved(name: &str, tld: &str, app: &AppState) -> Result<bool, anyhow::Error> {
+    if reserved_by_policy(name, tld, app) {
+        return Ok(true);
+    }
+
+    Ok(app.db.find_reservation(name, tld).await?.is_some())
+}
+
+/// Make sure `owner` holds fewer than `quota` domains on `tld`
+pub async fn check_quota(owner: &str, tld: &str, quota: usize, app: &AppState) -> Result<(), HttpResponse> {
+    let held = match app.db.find_by_owner(owner).await {
+        Ok(domains) => domains.iter().filter(|domain| domain.tld == tld).count(),
+        Err(err) => return Err(errors::internal(err)),
+    };
+
+    match held < quota {
+        true => Ok(()),
+        false => Err(errors::failed(
+            Code::QuotaExceeded,
+            "Domain quota reached",
+            format!("{owner} already holds {held} of at most {quota} domains on .{tld}."),
+        )),
+    }
+}
 
-        vec![DomainList {
-            taken,
-            domain: format!("{}.{}", name, tld),
-        }]
-    } else {
-        let mut result = Vec::new();
-        for tld in &*app.config.tld_list() {
-            let filter = doc! { "name": name, "tld": tld };
-            let taken = app.db.find_one(filter, None).await.unwrap().is_some();
+/// Registration status of each name and tld pair in `names`, with one query for domains and one for reservations
+pub async fn domain_statuses(names: &[(String, String)], app: &AppState) -> Result<Vec<DomainStatus>, anyhow::Error> {
+    let domains = app.db.find_many(names).await?;
+    let mut labels: Vec<String> = names.iter().map(|(name, _)| name.clone()).collect();
+    labels.sort_unstable();
+    labels.dedup();
+
+    let reservations = app.db.find_reservations(&labels).await?;
+    let (now, config) = (crate::time::now(), app.config.load());
+
+    let statuses = names.iter().map(|(name, tld)| match domains.iter().find(|domain| &domain.name == name && &domain.tld == tld) {
+        Some(domain) if domain.suspended.is_some() => DomainStatus::Suspended,
+        Some(domain) if domain.is_expired(now, &config) => DomainStatus::Expired,
+        Some(_) => DomainStatus::Registered,
+        None if reserved_by_policy(name, tld, app) => DomainStatus::Reserved,
+        None if reservations.iter().any(|reservation| &reservation.name == name && reservation.tld.as_ref().is_none_or(|reserved| reserved == tld)) => DomainStatus::Reserved,
+        None => DomainStatus::Available,
+    });
+
+    Ok(statuses.collect())
+}
 
-            result.push(DomainList {
-                taken,
+/// Whether `name` contains one of the offensive words of the config
+pub fn is_offensive(name: &str, config: &Config) -> bool {
+    let unicode = naming::to_unicode(name);
+    config.offen_words().iter().any(|word| unicode.contains(word))
+}
+
+/// Names that could replace a taken one, as name and tld pairs
+struct Candidates {
+    /// The same name on other open tlds
+    other_tlds: Vec<(String, String)>,
+    /// Variants of the name on its own tld
+    variants: Vec<(String, String)>,
+}
+
+/// Candidates replacing the taken `name` on `tld`, every one passes the naming rules and offensive words.
+/// Availability is left to the caller
+fn candidates(name: &str, tld: &str, config: &Config) -> Candidates {
+    let open = |tld: &str| config.tld(tld).is_some_and(|policy| policy.registration == Registration::Open);
+    let allowed = |(name, tld): (String, &str)| Some((naming::validate(&name, &config.naming_for(tld)).ok()?, tld.to_string())).filter(|(name, _)| !is_offensive(name, config));
+
+    let other_tlds = config.tld_list().into_iter().filter(|other| *other != tld && open(other)).map(|other| (name.to_string(), other));
+    let variants = naming::variants(&naming::to_unicode(name)).into_iter().filter(|_| open(tld)).map(|variant| (variant, tld));
+
+    Candidates {
+        other_tlds: other_tlds.filter_map(allowed).collect(),
+        variants: variants.filter_map(allowed).collect(),
+    }
+}
+
+/// Availability of `name` on `tld`, or on every tld, with alternatives to taken names when `suggest` is set
+pub async fn is_domain_taken(name: &str, tld: Option<&str>, suggest: bool, app: &AppState) -> Result<Vec<DomainList>, anyhow::Error> {
+    let config = app.config.load_full();
+    let tlds = match tld {
+        Some(tld) => vec![tld],
+        None => config.tld_list(),
+    };
+
+    // each tld may follow its own naming rules
+    let checked: Vec<(&str, Result<String, naming::Violation>)> = tlds.into_iter().map(|tld| (tld, naming::validate(name, &config.naming_for(tld)))).collect();
+    let valid: Vec<(String, String)> = checked.iter().filter_map(|(tld, validated)| Some((validated.as_ref().ok()?.clone(), tld.to_string()))).collect();
+    let mut statuses = domain_statuses(&valid, app).await?.into_iter();
+
+    let mut result: Vec<DomainList> = checked
+        .into_iter()
+        .map(|(tld, validated)| {
+            let (name, status, code) = match validated {
+                Ok(name) => (name, statuses.next().unwrap_or(DomainStatus::Invalid), None),
+                Err(violation) => (naming::to_ascii(name), DomainStatus::Invalid, Some(violation.code())),
+            };
+
+            DomainList {
+                taken: status != DomainStatus::Available,
                 domain: format!("{}.{}", name, tld),
-            });
+                status,
+                code,
+                suggestions: None,
+            }
+        })
+        .collect();
+
+    if !suggest {
+        return Ok(result);
+    }
+
+    let taken: Vec<(usize, Candidates)> = result
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| entry.taken && entry.status != DomainStatus::Invalid)
+        .filter_map(|(index, entry)| {
+            let (name, tld) = entry.domain.rsplit_once('.')?;
+            Some((index, candidates(name, tld, &config)))
+        })
+        .collect();
+
+    // candidates of every taken entry are checked together
+    let mut unique: Vec<(String, String)> = taken.iter().flat_map(|(_, candidates)| candidates.other_tlds.iter().chain(&candidates.variants).cloned()).collect();
+    unique.sort_unstable();
+    unique.dedup();
+
+    let available: HashSet<(String, String)> = unique
+        .iter()
+        .cloned()
+        .zip(domain_statuses(&unique, app).await?)
+        .filter(|(_, status)| *status == DomainStatus::Available)
+        .map(|(candidate, _)| candidate)
+        .collect();
+
+    for (index, Candidates { other_tlds, variants }) in taken {
+        let other_tlds: Vec<_> = other_tlds.into_iter().filter(|candidate| available.contains(candidate)).collect();
+        let variants: Vec<_> = variants.into_iter().filter(|candidate| available.contains(candidate)).collect();
+
+        // half of the suggestions keep the name, the rest of the room goes to variants
+        let keep = other_tlds.len().min((MAX_SUGGESTIONS / 2).max(MAX_SUGGESTIONS.saturating_sub(variants.len())));
+        let suggestions = other_tlds.into_iter().take(keep).chain(variants).take(MAX_SUGGESTIONS);
+
+        result[index].suggestions = Some(suggestions.map(|(name, tld)| format!("{name}.{tld}")).collect());
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(kind: RecordKind, name: &str, value: &str) -> Record {
+        Record {
+            kind,
+            name: name.into(),
+            value: value.into(),
+            ttl: None,
         }
-        result
+    }
+
+    #[test]
+    fn ip_with_records_becomes_apex_target() {
+        let (ip, records) = validate_records("https://x.example", &[record(RecordKind::Txt, "@", "hello")]).unwrap();
+
+        assert_eq!(ip, "https://x.example");
+        assert_eq!(records, vec![record(RecordKind::Url, "@", "https://x.example"), record(RecordKind::Txt, "@", "hello")]);
+        assert_eq!(target(&records).unwrap().value, ip);
+    }
+
+    #[test]
+    fn ip_must_match_apex_target() {
+        let records = [record(RecordKind::A, "@", "1.2.3.4")];
+
+        assert_eq!(validate_records("1.2.3.4", &records).unwrap().0, "1.2.3.4");
+        assert!(validate_records("5.6.7.8", &records).is_err());
+    }
+
+    #[test]
+    fn records_need_a_target() {
+        assert!(validate_records("", &[record(RecordKind::Txt, "@", "hello")]).is_err());
+        assert!(validate_records("", &[record(RecordKind::A, "www", "1.2.3.4")]).is_err());
+        assert!(validate_records("not a target", &[record(RecordKind::Txt, "@", "hello")]).is_err());
+    }
+
+    #[test]
+    fn legacy_ip_keeps_no_records() {
+        assert_eq!(validate_records("1.2.3.4", &[]).unwrap(), ("1.2.3.4".to_string(), Vec::new()));
     }
 }
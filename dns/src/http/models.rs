@@ -1,38 +1,212 @@
-use super::helpers::deserialize_lowercase;
+use super::{errors::Code, helpers::deserialize_lowercase};
+use crate::{
+    config::{Config, Tld},
+    kv::{ApiKey, Scope},
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Domain {
+    #[serde(default)]
     pub(crate) ip: String,
+    #[serde(default)]
+    pub(crate) records: Vec<Record>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) secret_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) owner: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) suspended: Option<Suspension>,
     #[serde(deserialize_with = "deserialize_lowercase")]
     pub(crate) tld: String,
     #[serde(deserialize_with = "deserialize_lowercase")]
     pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) created: u64,
+    #[serde(default)]
+    pub(crate) updated: u64,
+    /// When the registration ends unless renewed, unset on tlds without expiry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) expires: Option<u64>,
+    /// Since when the target has been failing health checks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) unreachable: Option<u64>,
+    /// Proof of control over the URL target, see `verify`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) verification: Option<Verification>,
 }
 
+/// A token the holder of a domain serves from its URL target
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Verification {
+    pub(crate) token: String,
+    /// The target the token was issued for
+    pub(crate) target: String,
+    /// When the token was found at the target
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) verified: Option<u64>,
+}
+
+/// A change of the target `ip` of a domain, kept for disputes and debugging
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Change {
+    pub(crate) name: String,
+    pub(crate) tld: String,
+    pub(crate) at: u64,
+    pub(crate) by: String,
+    pub(crate) old: String,
+    pub(crate) new: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Suspension {
+    pub(crate) reason: String,
+    pub(crate) since: u64,
+    #[serde(default)]
+    pub(crate) by: String,
+}
+
+/// A name held back from registration, on one tld or on every tld when `tld` is unset
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Reservation {
+    #[serde(deserialize_with = "deserialize_lowercase")]
+    pub(crate) name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) tld: Option<String>,
+    pub(crate) reason: String,
+    #[serde(default)]
+    pub(crate) since: u64,
+    #[serde(default)]
+    pub(crate) by: String,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum RecordKind {
+    A,
+    Aaaa,
+    Url,
+    Txt,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Record {
+    #[serde(rename = "type")]
+    pub(crate) kind: RecordKind,
+    #[serde(default = "apex", deserialize_with = "deserialize_lowercase")]
+    pub(crate) name: String,
+    pub(crate) value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) ttl: Option<u32>,
+}
+
+fn apex() -> String { "@".into() }
+
 #[derive(Debug, Serialize)]
 pub(crate) struct ResponseDomain {
     pub(crate) tld: String,
     pub(crate) ip: String,
     pub(crate) name: String,
+    pub(crate) records: Vec<Record>,
+    pub(crate) created: u64,
+    pub(crate) updated: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) expires: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) unreachable: Option<u64>,
+    /// When the holder proved control over the current URL target
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) verified: Option<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct UpdateDomain {
+    #[serde(default)]
     pub(crate) ip: String,
+    #[serde(default)]
+    pub(crate) records: Vec<Record>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Owner {
+    pub(crate) name: String,
+    pub(crate) token: String,
 }
 
+#[derive(Deserialize)]
+pub(crate) struct NewOwner {
+    #[serde(deserialize_with = "deserialize_lowercase")]
+    pub(crate) name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SecretKey {
+    pub(crate) secret_key: String,
+}
+
+/// A verification token and where it is looked for
 #[derive(Serialize)]
-pub(crate) struct Error {
+pub(crate) struct VerificationStatus {
+    pub(crate) token: String,
+    pub(crate) target: String,
+    pub(crate) url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) verified: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Renewal {
+    pub(crate) expires: u64,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct Transfer {
+    #[serde(deserialize_with = "deserialize_lowercase")]
+    pub(crate) owner: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct NewKey {
+    pub(crate) name: String,
+    #[serde(default = "default_scopes")]
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) expires: Option<u64>,
+    #[serde(default)]
+    pub(crate) description: String,
+}
+
+fn default_scopes() -> Vec<Scope> { vec![Scope::Create] }
+
+#[derive(Serialize)]
+pub(crate) struct KeyInfo {
+    pub(crate) name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) key: Option<String>,
+    #[serde(flatten)]
+    pub(crate) info: ApiKey,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct Suspend {
+    pub(crate) reason: String,
+}
+
+/// Body of every failed request, `code` is for clients to match on and `error` for people to read
+#[derive(Serialize)]
+pub(crate) struct Error<T = ()> {
+    pub(crate) code: Code,
     pub(crate) msg: &'static str,
     pub(crate) error: String,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub(crate) details: Option<T>,
 }
 
+/// Body of `429` responses, `error` and `msg` keep the values they had before error codes for older clients
 #[derive(Serialize)]
 pub(crate) struct Ratelimit {
-    pub(crate) msg: String,
+    pub(crate) code: Code,
     pub(crate) error: &'static str,
+    pub(crate) msg: String,
     pub(crate) after: u64,
 }
 
@@ -42,23 +216,310 @@ pub(crate) struct PaginationParams {
     pub(crate) page: Option<u32>,
     #[serde(alias = "s", alias = "size", alias = "l", alias = "limit")]
     pub(crate) page_size: Option<u32>,
+    pub(crate) tld: Option<String>,
+    pub(crate) prefix: Option<String>,
+    #[serde(alias = "q")]
+    pub(crate) search: Option<String>,
+    #[serde(default)]
+    pub(crate) sort: SortBy,
+    #[serde(default)]
+    pub(crate) order: Order,
+    pub(crate) cursor: Option<String>,
 }
 
 #[derive(Serialize)]
 pub(crate) struct PaginationResponse {
     pub(crate) domains: Vec<ResponseDomain>,
-    pub(crate) page: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) page: Option<u32>,
     pub(crate) limit: u32,
+    pub(crate) total: u64,
+    pub(crate) next: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SortBy {
+    Name,
+    #[default]
+    Created,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Order {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Position of a domain in a listing, rendered as `created:name:tld`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Cursor {
+    pub(crate) created: u64,
+    pub(crate) name: String,
+    pub(crate) tld: String,
 }
 
 #[derive(Deserialize)]
 pub(crate) struct DomainQuery {
     pub(crate) name: String,
     pub(crate) tld: Option<String>,
+    /// Suggest available alternatives to taken names
+    #[serde(default)]
+    pub(crate) suggest: bool,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ResolveQuery {
+    /// Domains as `name.tld` or `sub.name.tld`
+    pub(crate) domains: Vec<String>,
+}
+
+/// Outcome of resolving one domain, `code` tells why it was not found
+#[derive(Serialize)]
+pub(crate) struct Resolved {
+    pub(crate) domain: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) code: Option<Code>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub(crate) found: Option<ResponseDomain>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct TldQuery {
+    #[serde(default)]
+    pub(crate) details: bool,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct EventQuery {
+    /// Replay events after this id, like the `Last-Event-ID` header
+    pub(crate) after: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct TldInfo {
+    pub(crate) name: String,
+    #[serde(flatten)]
+    pub(crate) policy: Tld,
 }
 
 #[derive(Serialize)]
 pub(crate) struct DomainList {
     pub(crate) domain: String,
     pub(crate) taken: bool,
+    pub(crate) status: DomainStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) code: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) suggestions: Option<Vec<String>>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DomainStatus {
+    Available,
+    Registered,
+    Suspended,
+    Expired,
+    Reserved,
+    Invalid,
+}
+
+#[derive(Serialize)]
+pub(crate) struct DomainHistory {
+    pub(crate) domain: String,
+    pub(crate) created: u64,
+    pub(crate) updated: u64,
+    pub(crate) changes: Vec<Change>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct SuspendedDomain {
+    pub(crate) domain: String,
+    pub(crate) reason: String,
+    pub(crate) since: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ExpiredDomain {
+    pub(crate) domain: String,
+    pub(crate) expired: u64,
+    pub(crate) released: u64,
+}
+
+impl Record {
+    pub(crate) fn is_apex(&self) -> bool { self.name == "@" }
+}
+
+impl Domain {
+    /// When the registration ends, never while its tld has no `expiry` as it could not be renewed
+    pub(crate) fn expiry(&self, config: &Config) -> Option<u64> { self.expires.filter(|_| config.tld(&self.tld).and_then(|policy| policy.lifetime()).is_some()) }
+
+    /// Whether the registration ran out, the domain stops resolving until renewed
+    pub(crate) fn is_expired(&self, now: u64, config: &Config) -> bool { self.expiry(config).is_some_and(|expires| expires <= now) }
+
+    /// Verification issued for the current target, changing the target discards it
+    pub(crate) fn current_verification(&self) -> Option<&Verification> {
+        let verification = self.verification.as_ref()?;
+        (self.ip == verification.target).then_some(verification)
+    }
+
+    /// Records of the domain, falling back to the legacy single `ip` target
+    pub(crate) fn records(&self) -> Vec<Record> {
+        if !self.records.is_empty() || self.ip.is_empty() {
+            return self.records.clone();
+        }
+
+        vec![Record {
+            kind: RecordKind::infer(&self.ip),
+            name: apex(),
+            value: self.ip.clone(),
+            ttl: None,
+        }]
+    }
+
+    /// Records answering for `host` ("@" for the domain itself), preferring
+    /// the longest record name that equals `host` or is one of its parents.
+    pub(crate) fn resolve(&self, host: &str) -> Vec<Record> {
+        let records = self.records();
+        let mut candidate = Some(host);
+
+        while let Some(name) = candidate {
+            let matched: Vec<Record> = records.iter().filter(|record| record.name == name).cloned().collect();
+
+            if !matched.is_empty() {
+                return matched;
+            }
+
+            candidate = name.split_once('.').map(|(_, parent)| parent);
+        }
+
+        Vec::new()
+    }
+}
+
+impl RecordKind {
+    pub(crate) fn infer(value: &str) -> Self {
+        if value.parse::<std::net::Ipv4Addr>().is_ok() {
+            RecordKind::A
+        } else if value.parse::<std::net::Ipv6Addr>().is_ok() {
+            RecordKind::Aaaa
+        } else {
+            RecordKind::Url
+        }
+    }
+
+    /// Whether records of this kind can be the primary target served as `ip`
+    pub(crate) fn is_target(&self) -> bool { matches!(self, RecordKind::A | RecordKind::Aaaa | RecordKind::Url) }
+}
+
+/// Primary target among a set of records, as served in the legacy `ip` field
+pub(crate) fn target(records: &[Record]) -> Option<&Record> { records.iter().find(|record| record.kind.is_target()) }
+
+impl Cursor {
+    pub(crate) fn of(domain: &Domain) -> Self {
+        Cursor {
+            created: domain.created,
+            name: domain.name.clone(),
+            tld: domain.tld.clone(),
+        }
+    }
+
+    /// Ordering key under `sort`, names are unique per tld so ties can not happen
+    pub(crate) fn key(&self, sort: SortBy) -> (u64, &str, &str) {
+        match sort {
+            SortBy::Name => (0, &self.name, &self.tld),
+            SortBy::Created => (self.created, &self.name, &self.tld),
+        }
+    }
+}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { write!(f, "{}:{}:{}", self.created, self.name, self.tld) }
+}
+
+impl std::str::FromStr for Cursor {
+    type Err = ();
+
+    fn from_str(cursor: &str) -> Result<Self, Self::Err> {
+        let mut parts = cursor.split(':');
+
+        match (parts.next().and_then(|created| created.parse().ok()), parts.next(), parts.next(), parts.next()) {
+            (Some(created), Some(name), Some(tld), None) if !name.is_empty() && !tld.is_empty() => Ok(Cursor {
+                created,
+                name: name.to_string(),
+                tld: tld.to_string(),
+            }),
+            _ => Err(()),
+        }
+    }
+}
+
+impl VerificationStatus {
+    pub(crate) fn new(verification: Verification, url: String) -> Self {
+        VerificationStatus {
+            token: verification.token,
+            target: verification.target,
+            verified: verification.verified,
+            url,
+        }
+    }
+}
+
+impl From<Domain> for ResponseDomain {
+    fn from(domain: Domain) -> Self {
+        ResponseDomain {
+            verified: domain.current_verification().and_then(|verification| verification.verified),
+            records: domain.records(),
+            tld: domain.tld,
+            name: domain.name,
+            ip: domain.ip,
+            created: domain.created,
+            updated: domain.updated,
+            expires: domain.expires,
+            unreachable: domain.unreachable,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiry_follows_the_tld_policy() {
+        let mut config = Config::new();
+        let domain: Domain = serde_json::from_value(serde_json::json!({ "name": "example", "tld": "dev", "ip": "1.2.3.4", "expires": 10 })).unwrap();
+
+        config.settings.tlds.insert("dev".into(), Tld { expiry: Some(365), ..Tld::default() });
+        assert!(domain.is_expired(20, &config));
+        assert!(!domain.is_expired(5, &config));
+
+        // without an expiry the domain could not be renewed, so it never runs out
+        config.settings.tlds.insert("dev".into(), Tld::default());
+        assert_eq!(domain.expiry(&config), None);
+        assert!(!domain.is_expired(20, &config));
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let cursor = Cursor {
+            created: 1718000000,
+            name: "xn--mnchen-3ya".into(),
+            tld: "dev".into(),
+        };
+
+        assert_eq!(cursor.to_string(), "1718000000:xn--mnchen-3ya:dev");
+        assert_eq!(cursor.to_string().parse(), Ok(cursor));
+        assert_eq!("0:a:b".parse(), Ok(Cursor { created: 0, name: "a".into(), tld: "b".into() }));
+    }
+
+    #[test]
+    fn malformed_cursors_are_refused() {
+        for cursor in ["", "1718000000", "1718000000:example", "1718000000::dev", "1718000000:example:", "x:example:dev", "-1:example:dev", "18446744073709551616:example:dev", "1:example:dev:extra"] {
+            assert_eq!(cursor.parse::<Cursor>(), Err(()), "{cursor}");
+        }
+    }
 }
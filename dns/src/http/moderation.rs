@@ -0,0 +1,179 @@
+use super::{
+    errors::{self, Code},
+    helpers,
+    models::*,
+    AppState,
+};
+use crate::{events::EventKind, kv::Scope, naming, time};
+
+use actix_web::{
+    web::{self, Data},
+    HttpRequest, HttpResponse, Responder,
+};
+
+fn missing_reason(msg: &'static str) -> HttpResponse { errors::failed(Code::MissingReason, msg, "A reason is required.") }
+
+#[actix_web::delete("/admin/domain/{name}/{tld}")]
+pub(crate) async fn force_delete(path: web::Path<(String, String)>, app: Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(error) = helpers::authorize_key(&req, &app, Scope::Moderate).await {
+        return error;
+    }
+
+    let (name, tld) = path.into_inner();
+
+    let key = match helpers::domain_key(&name, &tld, &app).await {
+        Ok(key) => key,
+        Err(error) => return error,
+    };
+
+    match helpers::delete_domain(&key, &app).await {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => errors::domain_not_found(),
+        Err(err) => errors::internal(err),
+    }
+}
+
+#[actix_web::get("/admin/domain/{name}/{tld}/history")]
+pub(crate) async fn domain_history(path: web::Path<(String, String)>, app: Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(error) = helpers::authorize_key(&req, &app, Scope::Moderate).await {
+        return error;
+    }
+
+    let (name, tld) = path.into_inner();
+
+    match app.db.find(&naming::to_ascii(&name), &tld).await {
+        Ok(Some(domain)) => helpers::domain_history(domain, true, &app).await,
+        Ok(None) => errors::domain_not_found(),
+        Err(err) => errors::internal(err),
+    }
+}
+
+#[actix_web::post("/admin/domain/{name}/{tld}/suspend")]
+pub(crate) async fn suspend_domain(path: web::Path<(String, String)>, body: web::Json<Suspend>, app: Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(error) = helpers::authorize_key(&req, &app, Scope::Moderate).await {
+        return error;
+    }
+
+    let (name, tld) = path.into_inner();
+    let reason = body.into_inner().reason;
+
+    if reason.trim().is_empty() {
+        return missing_reason("Failed to suspend domain");
+    }
+
+    let key = match helpers::domain_key(&name, &tld, &app).await {
+        Ok(key) => key,
+        Err(error) => return error,
+    };
+
+    let suspension = Suspension {
+        reason,
+        since: time::now(),
+        by: helpers::key_name(&req),
+    };
+
+    match app.db.set_suspension(&key, Some(&suspension)).await {
+        Ok(true) => {
+            helpers::emit(EventKind::Suspended, &key, &app).await;
+            HttpResponse::Ok().json(suspension)
+        }
+        Ok(false) => errors::domain_not_found(),
+        Err(err) => errors::internal(err),
+    }
+}
+
+#[actix_web::delete("/admin/domain/{name}/{tld}/suspend")]
+pub(crate) async fn reinstate_domain(path: web::Path<(String, String)>, app: Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(error) = helpers::authorize_key(&req, &app, Scope::Moderate).await {
+        return error;
+    }
+
+    let (name, tld) = path.into_inner();
+
+    let key = match helpers::domain_key(&name, &tld, &app).await {
+        Ok(key) => key,
+        Err(error) => return error,
+    };
+
+    match app.db.set_suspension(&key, None).await {
+        Ok(true) => {
+            helpers::emit(EventKind::Reinstated, &key, &app).await;
+            HttpResponse::Ok().finish()
+        }
+        Ok(false) => errors::domain_not_found(),
+        Err(err) => errors::internal(err),
+    }
+}
+
+#[actix_web::get("/admin/reserved")]
+pub(crate) async fn list_reserved(app: Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(error) = helpers::authorize_key(&req, &app, Scope::Moderate).await {
+        return error;
+    }
+
+    match app.db.reservations().await {
+        Ok(reservations) => HttpResponse::Ok().json(reservations),
+        Err(err) => errors::internal(err),
+    }
+}
+
+#[actix_web::post("/admin/reserved")]
+pub(crate) async fn reserve_name(body: web::Json<Reservation>, app: Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(error) = helpers::authorize_key(&req, &app, Scope::Moderate).await {
+        return error;
+    }
+
+    let mut reservation = body.into_inner();
+    reservation.name = naming::to_ascii(&reservation.name);
+    reservation.tld = reservation.tld.map(|tld| tld.to_lowercase());
+
+    if reservation.reason.trim().is_empty() {
+        return missing_reason("Failed to reserve name");
+    }
+
+    if let Some(tld) = &reservation.tld {
+        if !app.config.load().tld_list().contains(&tld.as_str()) {
+            return errors::failed(Code::UnknownTld, "Failed to reserve name", format!("Non-existent TLD {tld}."));
+        }
+    }
+
+    reservation.since = time::now();
+    reservation.by = helpers::key_name(&req);
+
+    match app.db.reserve(&reservation).await {
+        Ok(_) => HttpResponse::Ok().json(reservation),
+        Err(err) => errors::internal(err),
+    }
+}
+
+#[actix_web::delete("/admin/reserved/{name}")]
+pub(crate) async fn release_name(path: web::Path<String>, app: Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(error) = helpers::authorize_key(&req, &app, Scope::Moderate).await {
+        return error;
+    }
+
+    let name = naming::to_ascii(&path.into_inner());
+
+    match app.db.release(&name, None).await {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => errors::error(Code::ReservationNotFound, format!("{name} is not reserved.")),
+        Err(err) => errors::internal(err),
+    }
+}
+
+#[actix_web::delete("/admin/reserved/{name}/{tld}")]
+pub(crate) async fn release_tld(path: web::Path<(String, String)>, app: Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(error) = helpers::authorize_key(&req, &app, Scope::Moderate).await {
+        return error;
+    }
+
+    let (name, tld) = path.into_inner();
+
+    let (name, tld) = (naming::to_ascii(&name), tld.to_lowercase());
+
+    match app.db.release(&name, Some(&tld)).await {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => errors::error(Code::ReservationNotFound, format!("{name}.{tld} is not reserved.")),
+        Err(err) => errors::internal(err),
+    }
+}
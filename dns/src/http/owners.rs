@@ -0,0 +1,156 @@
+use super::{
+    errors::{self, Code},
+    helpers,
+    models::*,
+    AppState,
+};
+use crate::{secret, store::Conflict};
+use regex::Regex;
+
+use actix_web::{
+    web::{self, Data},
+    HttpRequest, HttpResponse, Responder,
+};
+
+pub(crate) async fn create_owner(owner: web::Json<NewOwner>, app: Data<AppState>) -> impl Responder {
+    let valid_name = Regex::new(r"^[a-z0-9_-]{3,32}$").unwrap();
+    let name = owner.into_inner().name;
+
+    if !valid_name.is_match(&name) {
+        return errors::failed(Code::InvalidOwnerName, "Failed to create owner", "Owner names are 3 to 32 chars of a-z, 0-9, '-' or '_'.");
+    }
+
+    let token = secret::generate(48);
+    let owner = match secret::hash(&token) {
+        Ok(hash) => Owner { name, token: hash },
+        Err(err) => return errors::internal(err),
+    };
+
+    match app.db.find_owner(&owner.name).await {
+        Ok(Some(_)) => return errors::failed(Code::OwnerTaken, "Failed to create owner", format!("Owner {} already exists", owner.name)),
+        Ok(None) => {}
+        Err(err) => return errors::internal(err),
+    }
+
+    match app.db.insert_owner(&owner).await {
+        Ok(_) => HttpResponse::Ok().json(Owner { token, ..owner }),
+        Err(err) if err.is::<Conflict>() => errors::failed(Code::OwnerTaken, "Failed to create owner", format!("Owner {} already exists", owner.name)),
+        Err(err) => errors::internal(err),
+    }
+}
+
+#[actix_web::get("/owner/domains")]
+pub(crate) async fn owner_domains(app: Data<AppState>, req: HttpRequest) -> impl Responder {
+    let owner = match helpers::authorize_owner(&req, &app).await {
+        Ok(owner) => owner,
+        Err(error) => return error,
+    };
+
+    match app.db.find_by_owner(&owner.name).await {
+        Ok(domains) => HttpResponse::Ok().json(domains.into_iter().map(ResponseDomain::from).collect::<Vec<_>>()),
+        Err(err) => errors::internal(err),
+    }
+}
+
+#[actix_web::post("/owner/domains")]
+pub(crate) async fn claim_domain(claim: web::Json<SecretKey>, app: Data<AppState>, req: HttpRequest) -> impl Responder {
+    let owner = match helpers::authorize_owner(&req, &app).await {
+        Ok(owner) => owner,
+        Err(error) => return error,
+    };
+
+    let key = secret::digest(&claim.into_inner().secret_key);
+    let domain = match helpers::holder_domain(&key, &app).await {
+        Ok(domain) => domain,
+        Err(error) => return error,
+    };
+
+    if domain.owner.as_ref().is_some_and(|current| current != &owner.name) {
+        return errors::failed(Code::DomainTaken, "Failed to claim domain", "Domain is held by another account, ask its owner for a transfer.");
+    }
+
+    if let Some(quota) = app.config.load().tld(&domain.tld).and_then(|policy| policy.quota).filter(|_| domain.owner.is_none()) {
+        if let Err(error) = helpers::check_quota(&owner.name, &domain.tld, quota, &app).await {
+            return error;
+        }
+    }
+
+    match app.db.set_owner(&key, &owner.name).await {
+        Ok(true) => HttpResponse::Ok().json(ResponseDomain::from(domain)),
+        Ok(false) => errors::domain_not_found(),
+        Err(err) => errors::internal(err),
+    }
+}
+
+#[actix_web::post("/owner/domain/{name}/{tld}/rotate")]
+pub(crate) async fn rotate_owned(path: web::Path<(String, String)>, app: Data<AppState>, req: HttpRequest) -> impl Responder {
+    let (name, tld) = path.into_inner();
+    let owner = match helpers::authorize_owner(&req, &app).await {
+        Ok(owner) => owner,
+        Err(error) => return error,
+    };
+
+    let domain = match helpers::owned_domain(&name, &tld, &owner, &app).await.and_then(|domain| helpers::unlocked(&domain).map(|_| domain)) {
+        Ok(domain) => domain,
+        Err(error) => return error,
+    };
+
+    let secret_key = secret::generate(31);
+
+    match app.db.rotate(&domain.secret_key.unwrap_or_default(), &secret::digest(&secret_key)).await {
+        Ok(true) => HttpResponse::Ok().json(SecretKey { secret_key }),
+        Ok(false) => errors::domain_not_found(),
+        Err(err) => errors::internal(err),
+    }
+}
+
+#[actix_web::get("/owner/domain/{name}/{tld}/history")]
+pub(crate) async fn owned_history(path: web::Path<(String, String)>, app: Data<AppState>, req: HttpRequest) -> impl Responder {
+    let (name, tld) = path.into_inner();
+    let owner = match helpers::authorize_owner(&req, &app).await {
+        Ok(owner) => owner,
+        Err(error) => return error,
+    };
+
+    match helpers::owned_domain(&name, &tld, &owner, &app).await {
+        Ok(domain) => helpers::domain_history(domain, false, &app).await,
+        Err(error) => error,
+    }
+}
+
+#[actix_web::post("/owner/domain/{name}/{tld}/transfer")]
+pub(crate) async fn transfer_domain(path: web::Path<(String, String)>, transfer: web::Json<Transfer>, app: Data<AppState>, req: HttpRequest) -> impl Responder {
+    let (name, tld) = path.into_inner();
+    let owner = match helpers::authorize_owner(&req, &app).await {
+        Ok(owner) => owner,
+        Err(error) => return error,
+    };
+
+    let domain = match helpers::owned_domain(&name, &tld, &owner, &app).await.and_then(|domain| helpers::unlocked(&domain).map(|_| domain)) {
+        Ok(domain) => domain,
+        Err(error) => return error,
+    };
+
+    let target = transfer.into_inner().owner;
+    match app.db.find_owner(&target).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return errors::failed(Code::OwnerNotFound, "Failed to transfer domain", format!("Owner {target} does not exist")),
+        Err(err) => return errors::internal(err),
+    }
+
+    if let Some(quota) = app.config.load().tld(&domain.tld).and_then(|policy| policy.quota) {
+        if let Err(error) = helpers::check_quota(&target, &domain.tld, quota, &app).await {
+            return error;
+        }
+    }
+
+    // the previous key must stop working, the new owner rotates to obtain one
+    let key = domain.secret_key.clone().unwrap_or_default();
+    let secret_key = secret::generate(31);
+
+    match app.db.transfer(&key, &target, &secret::digest(&secret_key)).await {
+        Ok(true) => HttpResponse::Ok().json(ResponseDomain::from(domain)),
+        Ok(false) => errors::domain_not_found(),
+        Err(err) => errors::internal(err),
+    }
+}
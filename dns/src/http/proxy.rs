@@ -0,0 +1,175 @@
+use crate::config::SharedConfig;
+use actix_http::Protocol;
+use anyhow::{anyhow, Error};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::TcpStream,
+};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V1_MAX_LEN: usize = 107;
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Read the PROXY protocol header sent by trusted proxies and hand the connection
+/// to the HTTP service with the client address it carries. Other peers connect directly.
+pub(crate) async fn accept(mut stream: TcpStream, config: SharedConfig) -> Result<(TcpStream, Protocol, Option<SocketAddr>), Error> {
+    let peer = stream.peer_addr()?;
+
+    if !config.load().is_trusted_proxy(peer.ip()) {
+        return Ok((stream, Protocol::Http1, Some(peer)));
+    }
+
+    let client = match tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream)).await {
+        Ok(Ok(client)) => client.unwrap_or(peer),
+        Ok(Err(err)) => return Err(anyhow!("Invalid PROXY header from {peer}: {err}")),
+        Err(_) => return Err(anyhow!("No PROXY header from {peer}")),
+    };
+
+    Ok((stream, Protocol::Http1, Some(client)))
+}
+
+/// Consume a v1 or v2 header, `None` when it does not carry a client address
+async fn read_header(stream: &mut (impl AsyncRead + Unpin)) -> Result<Option<SocketAddr>, Error> {
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+
+    if &start == V2_SIGNATURE {
+        return read_v2(stream).await;
+    }
+
+    if !start.starts_with(b"PROXY ") {
+        return Err(anyhow!("missing signature"));
+    }
+
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(anyhow!("v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    parse_v1(std::str::from_utf8(&line)?.trim_end())
+}
+
+/// `PROXY TCP4 <src> <dst> <sport> <dport>` or `PROXY UNKNOWN ...`
+fn parse_v1(line: &str) -> Result<Option<SocketAddr>, Error> {
+    let parts: Vec<&str> = line.split(' ').collect();
+
+    match parts.get(1) {
+        Some(&"UNKNOWN") => Ok(None),
+        Some(&family @ ("TCP4" | "TCP6")) if parts.len() == 6 => {
+            let (ip, destination): (IpAddr, IpAddr) = (parts[2].parse()?, parts[3].parse()?);
+            let (port, _): (u16, u16) = (parts[4].parse()?, parts[5].parse()?);
+
+            match ip.is_ipv4() == (family == "TCP4") && destination.is_ipv4() == ip.is_ipv4() {
+                true => Ok(Some(SocketAddr::new(ip, port))),
+                false => Err(anyhow!("{family} header with addresses of another family")),
+            }
+        }
+        _ => Err(anyhow!("malformed v1 header")),
+    }
+}
+
+async fn read_v2(stream: &mut (impl AsyncRead + Unpin)) -> Result<Option<SocketAddr>, Error> {
+    let command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let mut body = vec![0u8; stream.read_u16().await? as usize];
+    stream.read_exact(&mut body).await?;
+
+    if command >> 4 != 2 {
+        return Err(anyhow!("unsupported version"));
+    }
+
+    // LOCAL connections, e.g. health checks of the proxy itself
+    if command & 0x0f == 0 {
+        return Ok(None);
+    }
+
+    match family >> 4 {
+        1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            Ok(Some(SocketAddr::new(ip.into(), u16::from_be_bytes([body[8], body[9]]))))
+        }
+        2 if body.len() >= 36 => {
+            let octets: [u8; 16] = body[..16].try_into()?;
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), u16::from_be_bytes([body[32], body[33]]))))
+        }
+        1 | 2 => Err(anyhow!("v2 address block too short")),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A v2 header with the PROXY command (0x21) or LOCAL (0x20), followed by `body`
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([command, family]);
+        header.extend((body.len() as u16).to_be_bytes());
+        header.extend(body);
+        header
+    }
+
+    async fn read(mut header: &[u8]) -> Result<Option<SocketAddr>, Error> { read_header(&mut header).await }
+
+    #[tokio::test]
+    async fn v1_headers() {
+        assert_eq!(read(b"PROXY TCP4 1.2.3.4 5.6.7.8 1111 80\r\n").await.unwrap(), Some("1.2.3.4:1111".parse().unwrap()));
+        assert_eq!(read(b"PROXY TCP6 2001:db8::1 2001:db8::2 1111 443\r\n").await.unwrap(), Some("[2001:db8::1]:1111".parse().unwrap()));
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v1_headers_leave_the_request() {
+        let mut stream: &[u8] = b"PROXY TCP4 1.2.3.4 5.6.7.8 1111 80\r\nGET / HTTP/1.1";
+        read_header(&mut stream).await.unwrap();
+        assert_eq!(stream, b"GET / HTTP/1.1");
+    }
+
+    #[tokio::test]
+    async fn malformed_v1_headers() {
+        assert!(read(b"PROXY TCP4 1.2.3.4 5.6.7.8 1111\r\n").await.is_err());
+        assert!(read(b"PROXY TCP4 1.2.3.4 5.6.7.8 1111 port\r\n").await.is_err());
+        assert!(read(b"PROXY TCP4 not.an.ip 5.6.7.8 1111 80\r\n").await.is_err());
+        assert!(read(b"PROXY TCP4 2001:db8::1 2001:db8::2 1111 80\r\n").await.is_err());
+        assert!(read(b"GET / HTTP/1.1\r\n").await.is_err());
+        // truncated before the line ends, and longer than a v1 header can be
+        assert!(read(b"PROXY TCP4 1.2.3.4").await.is_err());
+        assert!(read(format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX_LEN)).as_bytes()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_headers() {
+        let ipv4 = [[1, 2, 3, 4], [5, 6, 7, 8]].concat().into_iter().chain(1111u16.to_be_bytes()).chain(80u16.to_be_bytes()).collect::<Vec<_>>();
+        assert_eq!(read(&v2(0x21, 0x11, &ipv4)).await.unwrap(), Some("1.2.3.4:1111".parse().unwrap()));
+
+        let src: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let ipv6 = [src.octets(), Ipv6Addr::LOCALHOST.octets()].concat().into_iter().chain(1111u16.to_be_bytes()).chain(443u16.to_be_bytes()).collect::<Vec<_>>();
+        assert_eq!(read(&v2(0x21, 0x21, &ipv6)).await.unwrap(), Some("[2001:db8::1]:1111".parse().unwrap()));
+
+        // health checks of the proxy itself carry no client
+        assert_eq!(read(&v2(0x20, 0x00, &[])).await.unwrap(), None);
+        assert_eq!(read(&v2(0x20, 0x11, &ipv4)).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn malformed_v2_headers() {
+        assert!(read(&v2(0x11, 0x11, &[0; 12])).await.is_err());
+        assert!(read(&v2(0x21, 0x11, &[0; 4])).await.is_err());
+        assert!(read(&v2(0x21, 0x21, &[0; 12])).await.is_err());
+
+        // a length past what was sent
+        let mut oversized = v2(0x21, 0x11, &[0; 12]);
+        oversized[14..16].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert!(read(&oversized).await.is_err());
+
+        assert!(read(&V2_SIGNATURE[..8]).await.is_err());
+    }
+}
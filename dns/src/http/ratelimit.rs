@@ -1,61 +1,285 @@
-use super::models::Ratelimit;
-use actix_web::{dev::ServiceRequest, web, HttpResponse, HttpResponseBuilder};
+use super::{
+    errors::Code,
+    helpers,
+    models::Ratelimit,
+    AppState,
+};
+use crate::{
+    config::LimitBy,
+    metrics::METRICS,
+    time,
+};
 
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER},
+    web, Error, HttpResponse,
+};
+
+use futures::future::{ready, LocalBoxFuture, Ready};
+use ipnet::Ipv6Net;
 use std::{
+    collections::HashMap,
     net::{IpAddr, SocketAddr},
+    rc::Rc,
     str::FromStr,
-    time::{SystemTime, UNIX_EPOCH},
+    sync::Mutex,
+    time::Instant,
 };
 
-use actix_governor::{
-    governor::clock::{Clock, DefaultClock, QuantaInstant},
-    governor::NotUntil,
-    KeyExtractor, SimpleKeyExtractionError,
-};
+/// Buckets are only pruned once there are this many
+const MAX_BUCKETS: usize = 10_000;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) struct RealIpKeyExtractor;
 
-impl KeyExtractor for RealIpKeyExtractor {
-    type Key = IpAddr;
-    type KeyExtractionError = SimpleKeyExtractionError<&'static str>;
+impl RealIpKeyExtractor {
+    /// Address of the client, looking through `X-Forwarded-For` when the peer is a trusted proxy.
+    /// The nearest address not belonging to a trusted proxy is used, as earlier ones can be forged.
+    pub(crate) fn extract(&self, req: &ServiceRequest) -> Option<IpAddr> {
+        let peer = req.peer_addr()?.ip();
+        let config = req.app_data::<web::Data<AppState>>()?.config.load();
 
-    fn extract(&self, req: &ServiceRequest) -> Result<Self::Key, Self::KeyExtractionError> {
-        let reverse_proxy_ip = req
-            .app_data::<web::Data<super::AppState>>()
-            .map(|ip| ip.get_ref().trusted.to_owned())
-            .unwrap_or_else(|| IpAddr::from_str("0.0.0.0").unwrap());
+        if !config.is_trusted_proxy(peer) {
+            return Some(peer);
+        }
 
-        let peer_ip = req.peer_addr().map(|socket| socket.ip());
-        let connection_info = req.connection_info();
+        let forwarded: Vec<IpAddr> = req
+            .headers()
+            .get_all("x-forwarded-for")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|ip| ip.trim().parse().ok())
+            .collect();
 
-        match peer_ip {
-            Some(peer) if peer == reverse_proxy_ip => connection_info
+        match forwarded.iter().rev().find(|ip| !config.is_trusted_proxy(**ip)) {
+            Some(ip) => Some(*ip),
+            None => req
+                .connection_info()
                 .realip_remote_addr()
-                .ok_or_else(|| SimpleKeyExtractionError::new("Could not extract real IP address from request"))
-                .and_then(|str| {
-                    SocketAddr::from_str(str)
-                        .map(|socket| socket.ip())
-                        .or_else(|_| IpAddr::from_str(str))
-                        .map_err(|_| SimpleKeyExtractionError::new("Could not extract real IP address from request"))
-                }),
-            _ => connection_info
-                .peer_addr()
-                .ok_or_else(|| SimpleKeyExtractionError::new("Could not extract peer IP address from request"))
-                .and_then(|str| SocketAddr::from_str(str).map_err(|_| SimpleKeyExtractionError::new("Could not extract peer IP address from request")))
-                .map(|socket| socket.ip()),
-        }
-    }
-
-    fn exceed_rate_limit_response(&self, negative: &NotUntil<QuantaInstant>, mut response: HttpResponseBuilder) -> HttpResponse {
-        let current_unix_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs();
-        let wait_time = negative.wait_time_from(DefaultClock::default().now()).as_secs();
-        let wait_time_unix = current_unix_timestamp + negative.wait_time_from(DefaultClock::default().now()).as_secs();
-
-        response.json(Ratelimit {
-            after: wait_time_unix,
-            error: "ratelimited_endpoint",
-            msg: format!("Too many requests, try again in {wait_time}s"),
+                .and_then(|str| SocketAddr::from_str(str).map(|socket| socket.ip()).or_else(|_| IpAddr::from_str(str)).ok())
+                .or(Some(peer)),
+        }
+    }
+}
+
+/// Rate limit bucket of an address, IPv6 clients are grouped by `ipv6_prefix`
+fn address_bucket(ip: IpAddr, ipv6_prefix: u8) -> String {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => v4.to_string(),
+            None => Ipv6Net::new(v6, ipv6_prefix).map(|net| net.trunc().to_string()).unwrap_or_else(|_| v6.to_string()),
+        },
+        IpAddr::V4(v4) => v4.to_string(),
+    }
+}
+
+/// A token bucket refilling `requests` tokens per `period`
+struct Bucket {
+    tokens: f64,
+    capacity: f64,
+    rate: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+}
+
+/// Outcome of a rate limited request, reported in the `RateLimit-*` headers
+struct Decision {
+    allowed: bool,
+    limit: u32,
+    period: u64,
+    remaining: u32,
+    reset: u64,
+    retry_after: u64,
+}
+
+impl Decision {
+    fn write(&self, headers: &mut HeaderMap) {
+        let mut insert = |name: &'static str, value: String| {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(HeaderName::from_static(name), value);
+            }
+        };
+
+        insert("ratelimit-limit", self.limit.to_string());
+        insert("ratelimit-remaining", self.remaining.to_string());
+        insert("ratelimit-reset", self.reset.to_string());
+        insert("ratelimit-policy", format!("{};w={}", self.limit, self.period));
+
+        if !self.allowed {
+            headers.insert(RETRY_AFTER, HeaderValue::from(self.retry_after));
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Limiter {
+    buckets: Mutex<HashMap<(String, String), Bucket>>,
+}
+
+impl Limiter {
+    /// Give back the token taken from a bucket by `check`
+    fn refund(&self, route: &str, key: String) {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|err| err.into_inner());
+
+        if let Some(bucket) = buckets.get_mut(&(route.to_string(), key)) {
+            bucket.tokens = (bucket.tokens + 1.0).min(bucket.capacity);
+        }
+    }
+
+    fn check(&self, route: &str, key: String, requests: u32, period: u64) -> Decision {
+        let now = Instant::now();
+        let (capacity, rate) = (requests as f64, requests as f64 / period as f64);
+        let mut buckets = self.buckets.lock().unwrap_or_else(|err| err.into_inner());
+
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, bucket| {
+                bucket.refill(now);
+                bucket.tokens < bucket.capacity
+            });
+        }
+
+        let bucket = buckets.entry((route.to_string(), key)).or_insert(Bucket {
+            tokens: capacity,
+            capacity,
+            rate,
+            updated: now,
+        });
+
+        // the limit may have changed with a config reload
+        (bucket.capacity, bucket.rate) = (capacity, rate);
+        bucket.refill(now);
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        Decision {
+            allowed,
+            limit: requests,
+            period,
+            remaining: bucket.tokens.floor() as u32,
+            reset: ((capacity - bucket.tokens) / rate).ceil() as u64,
+            retry_after: ((1.0 - bucket.tokens).max(0.0) / rate).ceil() as u64,
+        }
+    }
+}
+
+/// Name of the key the request is authorized with, checking the secret so no one else can use or drain its bucket
+async fn authorized_key(req: &ServiceRequest, app: &AppState) -> Option<String> {
+    let key = helpers::check_key(req.request(), app).await.ok()??;
+    let (name, _) = super::get_token(req.request()).ok()?;
+
+    (!key.is_expired()).then(|| name.to_string())
+}
+
+/// Bucket of the address the request comes from
+fn address(req: &ServiceRequest, app: &AppState) -> String {
+    let ip = RealIpKeyExtractor
+        .extract(req)
+        .map(|ip| address_bucket(ip, app.config.load().server.proxy.ipv6_prefix))
+        .unwrap_or_default();
+
+    format!("ip:{ip}")
+}
+
+/// Take a token for the request from the bucket of the matched route, `None` when the route is not limited
+async fn decide(req: &ServiceRequest, app: &AppState) -> Option<Decision> {
+    // versioned and unversioned paths share one limit
+    let pattern = req.match_pattern()?;
+    let route = format!("{} {}", req.method(), pattern.strip_prefix("/v1").unwrap_or(&pattern));
+    let limit = app.config.load().ratelimit(&route)?.clone();
+    let address = address(req, app);
+
+    // the address pays for checking the secret, so guessing secrets is limited like any other request
+    let mut decision = app.limiter.check(&route, address.clone(), limit.requests, limit.period);
+
+    if decision.allowed && limit.by == LimitBy::Key && req.headers().contains_key("authorization") {
+        if let Some(name) = authorized_key(req, app).await {
+            app.limiter.refund(&route, address);
+            decision = app.limiter.check(&route, format!("key:{name}"), limit.keys.get(&name).copied().unwrap_or(limit.requests), limit.period);
+        }
+    }
+
+    if !decision.allowed {
+        METRICS.ratelimited.with_label_values(&[&route]).inc();
+    }
+
+    Some(decision)
+}
+
+/// Applies the rate limit configured for the matched route
+pub(crate) struct RateLimiter;
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimited<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future { ready(Ok(RateLimited { service: Rc::new(service) })) }
+}
+
+pub(crate) struct RateLimited<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimited<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let decision = match req.app_data::<web::Data<AppState>>().cloned() {
+                Some(app) => decide(&req, &app).await,
+                None => None,
+            };
+
+            match decision {
+                Some(decision) if !decision.allowed => {
+                    let mut response = HttpResponse::TooManyRequests().json(Ratelimit {
+                        code: Code::Ratelimited,
+                        error: "ratelimited_endpoint",
+                        msg: format!("Too many requests, try again in {}s", decision.retry_after),
+                        after: time::now() + decision.retry_after,
+                    });
+
+                    response.extensions_mut().insert(Code::Ratelimited);
+
+                    decision.write(response.headers_mut());
+                    Ok(req.into_response(response).map_into_right_body())
+                }
+                decision => {
+                    let mut response = service.call(req).await?;
+                    if let Some(decision) = decision {
+                        decision.write(response.headers_mut());
+                    }
+
+                    Ok(response.map_into_left_body())
+                }
+            }
         })
     }
 }
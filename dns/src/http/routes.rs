@@ -1,7 +1,18 @@
-use super::{models::*, AppState};
-use crate::{http::helpers, kv, secret};
-use futures::stream::StreamExt;
-use mongodb::{bson::doc, options::FindOptions};
+use super::{
+    errors::{self, Code},
+    models::*,
+    AppState,
+};
+use crate::{
+    config::{Config, Registration, Tld},
+    events::{self, EventKind},
+    http::helpers,
+    kv::Scope,
+    metrics::METRICS,
+    naming, secret,
+    store::{Conflict, Search},
+    time, verify,
+};
 use std::env;
 
 use actix_web::{
@@ -12,146 +23,578 @@ use actix_web::{
 #[actix_web::get("/")]
 pub(crate) async fn index() -> impl Responder {
     HttpResponse::Ok().body(format!(
-		  "webxDNS v{}!\n\nThe available endpoints are:\n\n - [GET] /domains\n - [GET] /domain/{{name}}/{{tld}}\n - [POST] /domain\n - [PUT] /domain/{{key}}\n - [DELETE] /domain/{{key}}\n - [GET] /tlds\n\nRatelimits are as follows: 5 requests per 10 minutes on `[POST] /domain`.\n\nCode link: https://github.com/face-hh/webx/tree/master/dns",env!("CARGO_PKG_VERSION")),
+		  "webxDNS v{}!\n\nThe available endpoints are:\n\n - [GET] /domains\n - [POST] /domains/resolve\n - [GET] /domain/{{name}}/{{tld}}\n - [POST] /domain\n - [PUT] /domain/{{key}}\n - [DELETE] /domain/{{key}}\n - [POST] /domain/{{key}}/rotate\n - [POST] /domain/{{key}}/renew\n - [GET] /domain/{{key}}/history\n - [POST] /domain/{{key}}/verification\n - [POST] /domain/{{key}}/verification/check\n - [PUT] /domain/{{key}}/sub/{{sub}}\n - [DELETE] /domain/{{key}}/sub/{{sub}}\n - [GET] /tlds\n - [GET] /events\n - [POST] /owner\n - [GET] /owner/domains\n - [POST] /owner/domains\n - [POST] /owner/domain/{{name}}/{{tld}}/rotate\n - [POST] /owner/domain/{{name}}/{{tld}}/transfer\n - [GET] /owner/domain/{{name}}/{{tld}}/history\n\nEvery endpoint is also served under /v1, errors carry a stable `code`.\nRate limits are reported in the `RateLimit-*` headers of each limited endpoint.\n\nCode link: https://github.com/face-hh/webx/tree/master/dns",env!("CARGO_PKG_VERSION")),
 	 )
 }
 
-pub(crate) async fn create_logic(domain: Domain, app: &AppState) -> Result<Domain, HttpResponse> {
+/// Changes made with the secret key of a domain are recorded as made by its holder
+const SECRET_KEY: &str = "secret_key";
+
+/// Domains one request can resolve
+const MAX_RESOLVE: usize = 100;
+
+fn rejected(code: Code, error: String) -> HttpResponse { errors::failed(code, "Failed to create domain", error) }
+
+/// Count a refused registration by its error code
+fn refused(error: HttpResponse) -> HttpResponse {
+    if let Some(code) = error.extensions().get::<Code>().filter(|code| **code != Code::Internal) {
+        METRICS.rejections.with_label_values(&[code.as_str()]).inc();
+    }
+
+    error
+}
+
+/// Validate and insert a new domain, `elevated` registrations come from an API key
+pub(crate) async fn create_logic(mut domain: Domain, app: &AppState, elevated: bool) -> Result<Domain, HttpResponse> {
+    let config = app.config.load_full();
+    (domain.suspended, domain.unreachable, domain.verification) = (None, None, None);
+    (domain.ip, domain.records) = helpers::validate_records(&domain.ip, &domain.records)?;
     helpers::validate_ip(&domain)?;
 
-    if !app.config.tld_list().contains(&domain.tld.as_str()) || !domain.name.chars().all(|c| c.is_alphabetic() || c == '-') || domain.name.len() > 24 {
-        return Err(HttpResponse::BadRequest().json(Error {
-            msg: "Failed to create domain",
-            error: "Invalid name, non-existent TLD, or name too long (24 chars).".into(),
-        }));
+    let Some(policy) = config.tld(&domain.tld) else {
+        return Err(rejected(Code::UnknownTld, format!("The TLD '{}' does not exist.", domain.tld)));
+    };
+
+    match policy.registration {
+        Registration::Open => {}
+        Registration::Invite if elevated => {}
+        Registration::Invite => return Err(rejected(Code::RegistrationInvite, format!("Registering on .{} requires an API key.", domain.tld))),
+        Registration::Closed => return Err(rejected(Code::RegistrationClosed, format!("Registration on .{} is closed.", domain.tld))),
     }
 
-    if app.config.offen_words().iter().any(|word| domain.name.contains(word)) {
-        return Err(HttpResponse::BadRequest().json(Error {
-            msg: "Failed to create domain",
-            error: "The given domain name is offensive.".into(),
-        }));
+    domain.name = match naming::validate(&domain.name, &config.naming_for(&domain.tld)) {
+        Ok(name) => name,
+        Err(violation) => return Err(rejected(Code::Naming(violation.code()), violation.message())),
+    };
+
+    if helpers::is_offensive(&domain.name, &config) {
+        return Err(rejected(Code::Offensive, "The given domain name is offensive.".into()));
     }
 
-    let existing_domain = app
-        .db
-        .find_one(doc! { "name": &domain.name, "tld": &domain.tld }, None)
-        .await
-        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+    if helpers::is_reserved(&domain.name, &domain.tld, app).await.map_err(errors::internal)? {
+        return Err(rejected(Code::Reserved, "The given domain name is reserved.".into()));
+    }
+
+    if let Some(quota) = policy.quota {
+        match &domain.owner {
+            Some(owner) => helpers::check_quota(owner, &domain.tld, quota, app).await?,
+            None if !elevated => return Err(rejected(Code::OwnerRequired, format!("Registering on .{} requires an owner account.", domain.tld))),
+            None => {}
+        }
+    }
+
+    let existing_domain = app.db.find(&domain.name, &domain.tld).await.map_err(errors::internal)?;
 
     if existing_domain.is_some() {
-        return Err(HttpResponse::Conflict().finish());
+        return Err(rejected(Code::DomainTaken, format!("{}.{} is already registered.", domain.name, domain.tld)));
     }
 
-    app.db.insert_one(&domain, None).await.map_err(|_| HttpResponse::Conflict().finish())?;
+    (domain.created, domain.updated) = (time::now(), time::now());
+    domain.expires = policy.lifetime().map(|lifetime| domain.created + lifetime);
+    // lost a race with another registration of the same name
+    app.db.insert(&domain).await.map_err(|err| match err.is::<Conflict>() {
+        true => rejected(Code::DomainTaken, format!("{}.{} is already registered.", domain.name, domain.tld)),
+        false => errors::internal(err),
+    })?;
+    helpers::changed(EventKind::Created, &domain, app).await;
 
     Ok(domain)
 }
 
-pub(crate) async fn create_domain(domain: web::Json<Domain>, app: Data<AppState>) -> impl Responder {
+pub(crate) async fn create_domain(domain: web::Json<Domain>, app: Data<AppState>, req: HttpRequest) -> impl Responder {
     let secret_key = secret::generate(31);
     let mut domain = domain.into_inner();
-    domain.secret_key = Some(secret_key);
+    domain.secret_key = Some(secret::digest(&secret_key));
+    domain.owner = None;
+
+    // registering with owner credentials assigns the domain to that account
+    if req.headers().contains_key("authorization") {
+        match helpers::authorize_owner(&req, &app).await {
+            Ok(owner) => domain.owner = Some(owner.name),
+            Err(error) => return error,
+        }
+    }
 
-    match create_logic(domain, app.as_ref()).await {
-        Ok(domain) => HttpResponse::Ok().json(domain),
-        Err(error) => error,
+    match create_logic(domain, app.as_ref(), false).await {
+        Ok(domain) => HttpResponse::Ok().json(Domain {
+            secret_key: Some(secret_key),
+            ..domain
+        }),
+        Err(error) => refused(error),
     }
 }
 
 #[actix_web::post("/registry/domain")]
 pub(crate) async fn elevated_domain(domain: web::Json<Domain>, app: Data<AppState>, req: HttpRequest) -> impl Responder {
-    match super::get_token(&req) {
-        Ok((name, key)) => match kv::get(&app.config.server.key_db, &name.to_string()) {
-            Ok(value) => macros_rs::exp::then!(
-                value != key,
-                return HttpResponse::Unauthorized().json(Error {
-                    msg: "Invalid authorization header",
-                    error: "Token is invalid".into(),
-                })
-            ),
-            Err(err) => {
-                return HttpResponse::InternalServerError().json(Error {
-                    msg: "Failed to fetch authorization header",
-                    error: err.to_string(),
-                })
-            }
-        },
-        Err(err) => {
-            return HttpResponse::Unauthorized().json(Error {
-                msg: "Authorization failed",
-                error: err.to_string(),
-            })
-        }
-    };
+    if let Err(error) = helpers::authorize_key(&req, &app, Scope::Create).await {
+        return error;
+    }
 
     let secret_key = secret::generate(31);
     let mut domain = domain.into_inner();
-    domain.secret_key = Some(secret_key);
+    domain.secret_key = Some(secret::digest(&secret_key));
+    domain.owner = None;
+
+    match create_logic(domain, app.as_ref(), true).await {
+        Ok(domain) => HttpResponse::Ok().json(Domain {
+            secret_key: Some(secret_key),
+            ..domain
+        }),
+        Err(error) => refused(error),
+    }
+}
+
+#[actix_web::get("/registry/domain/{name}/{tld}")]
+pub(crate) async fn elevated_get(path: web::Path<(String, String)>, app: Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(error) = helpers::authorize_key(&req, &app, Scope::ReadAdmin).await {
+        return error;
+    }
+
+    let (name, tld) = path.into_inner();
+
+    match app.db.find(&naming::to_ascii(&name), &tld).await {
+        Ok(Some(domain)) => HttpResponse::Ok().json(Domain { secret_key: None, ..domain }),
+        Ok(None) => errors::domain_not_found(),
+        Err(err) => errors::internal(err),
+    }
+}
+
+#[actix_web::put("/registry/domain/{name}/{tld}")]
+pub(crate) async fn elevated_update(path: web::Path<(String, String)>, domain_update: web::Json<UpdateDomain>, app: Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(error) = helpers::authorize_key(&req, &app, Scope::UpdateAny).await {
+        return error;
+    }
+
+    let (name, tld) = path.into_inner();
+    let UpdateDomain { ip, records } = domain_update.into_inner();
+
+    let domain = match app.db.find(&naming::to_ascii(&name), &tld).await {
+        Ok(Some(domain)) => domain,
+        Ok(None) => return errors::domain_not_found(),
+        Err(err) => return errors::internal(err),
+    };
+
+    let (key, records) = (domain.secret_key.clone().unwrap_or_default(), helpers::update_records(&domain, &ip, records));
+
+    let (ip, records) = match helpers::validate_records(&ip, &records).and_then(|(ip, records)| helpers::validate_target(&ip).map(|_| (ip, records))) {
+        Ok(res) => res,
+        Err(error) => return error,
+    };
+
+    match app.db.update(&key, &ip, &records, &format!("api_key:{}", helpers::key_name(&req))).await {
+        Ok(true) => {
+            helpers::emit(EventKind::Updated, &key, &app).await;
+            HttpResponse::Ok().json(UpdateDomain { ip, records })
+        }
+        Ok(false) => errors::domain_not_found(),
+        Err(err) => errors::internal(err),
+    }
+}
 
-    match create_logic(domain, app.as_ref()).await {
-        Ok(domain) => HttpResponse::Ok().json(domain),
-        Err(error) => error,
+#[actix_web::delete("/registry/domain/{name}/{tld}")]
+pub(crate) async fn elevated_delete(path: web::Path<(String, String)>, app: Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(error) = helpers::authorize_key(&req, &app, Scope::DeleteAny).await {
+        return error;
+    }
+
+    let (name, tld) = path.into_inner();
+
+    let key = match helpers::domain_key(&name, &tld, &app).await {
+        Ok(key) => key,
+        Err(error) => return error,
+    };
+
+    match helpers::delete_domain(&key, &app).await {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => errors::domain_not_found(),
+        Err(err) => errors::internal(err),
     }
 }
 
 #[actix_web::get("/domain/{name}/{tld}")]
-pub(crate) async fn get_domain(path: web::Path<(String, String)>, app: Data<AppState>) -> impl Responder {
+pub(crate) async fn get_domain(path: web::Path<(String, String)>, app: Data<AppState>, req: HttpRequest) -> impl Responder {
     let (name, tld) = path.into_inner();
-    let filter = doc! { "name": name, "tld": tld };
+    let (host, name) = match name.rsplit_once('.') {
+        Some((host, name)) => (host.to_string(), name.to_string()),
+        None => ("@".to_string(), name),
+    };
+    let name = naming::to_ascii(&name);
+    let config = app.config.load_full();
 
-    match app.db.find_one(filter, None).await {
-        Ok(Some(domain)) => HttpResponse::Ok().json(ResponseDomain {
-            tld: domain.tld,
-            name: domain.name,
-            ip: domain.ip,
-        }),
-        Ok(None) => HttpResponse::NotFound().finish(),
-        Err(_) => HttpResponse::InternalServerError().finish(),
+    // unknown tlds share a label so lookups can not grow the metrics
+    let label = match config.tld_list().contains(&tld.as_str()) {
+        true => tld.as_str(),
+        false => "-",
+    };
+    METRICS.lookups.with_label_values(&[label]).inc();
+
+    match app.cache.find(&name, &tld, app.db.as_ref(), &config).await {
+        Ok(Some(Domain { suspended: Some(suspension), name, tld, .. })) => errors::detailed(
+            Code::DomainSuspended,
+            "Domain is suspended",
+            format!("{name}.{tld} was suspended: {}", suspension.reason),
+            Some(SuspendedDomain {
+                domain: format!("{name}.{tld}"),
+                reason: suspension.reason,
+                since: suspension.since,
+            }),
+        ),
+        Ok(Some(domain)) if domain.is_expired(time::now(), &config) => {
+            let (name, tld, expires) = (&domain.name, &domain.tld, domain.expires.unwrap_or_default());
+
+            errors::detailed(
+                Code::DomainExpired,
+                "Domain has expired",
+                format!("{name}.{tld} expired and can be renewed by its holder until it is released."),
+                Some(ExpiredDomain {
+                    released: expires.saturating_add(config.grace_for(tld)),
+                    domain: format!("{name}.{tld}"),
+                    expired: expires,
+                }),
+            )
+        }
+        Ok(Some(domain)) if host == "@" => helpers::conditional(&req, &ResponseDomain::from(domain), config.settings.cache.max_age),
+        Ok(Some(domain)) => match domain.resolve(&host) {
+            records if records.is_empty() => errors::error(Code::RecordNotFound, format!("{host}.{}.{} has no records.", domain.name, domain.tld)),
+            records => helpers::conditional(&req, &subdomain_response(domain, &host, records), config.settings.cache.max_age),
+        },
+        Ok(None) => errors::domain_not_found(),
+        Err(err) => errors::internal(err),
+    }
+}
+
+fn subdomain_response(domain: Domain, host: &str, records: Vec<Record>) -> ResponseDomain {
+    ResponseDomain {
+        ip: target(&records).map(|record| record.value.clone()).unwrap_or_default(),
+        name: format!("{host}.{}", domain.name),
+        tld: domain.tld,
+        created: domain.created,
+        updated: domain.updated,
+        expires: domain.expires,
+        unreachable: domain.unreachable,
+        verified: None,
+        records,
+    }
+}
+
+#[actix_web::put("/domain/{key}/sub/{sub}")]
+pub(crate) async fn update_subdomain(path: web::Path<(String, String)>, sub_update: web::Json<UpdateDomain>, app: Data<AppState>) -> impl Responder {
+    let (key, sub) = path.into_inner();
+    let (key, sub) = (secret::digest(&key), sub.to_lowercase());
+
+    let sub_records = match helpers::validate_subdomain(&sub, sub_update.into_inner()) {
+        Ok(records) => records,
+        Err(error) => return error,
+    };
+
+    let domain = match helpers::holder_domain(&key, &app).await {
+        Ok(domain) => domain,
+        Err(error) => return error,
+    };
+
+    let mut records: Vec<Record> = domain.records().into_iter().filter(|record| record.name != sub).collect();
+    records.extend(sub_records.iter().cloned());
+
+    let (ip, records) = match helpers::validate_records(&domain.ip, &records) {
+        Ok(res) => res,
+        Err(error) => return error,
+    };
+
+    match app.db.update(&key, &ip, &records, SECRET_KEY).await {
+        Ok(true) => {
+            helpers::emit(EventKind::Updated, &key, &app).await;
+            HttpResponse::Ok().json(subdomain_response(domain, &sub, sub_records))
+        }
+        Ok(false) => errors::domain_not_found(),
+        Err(err) => errors::internal(err),
+    }
+}
+
+#[actix_web::delete("/domain/{key}/sub/{sub}")]
+pub(crate) async fn delete_subdomain(path: web::Path<(String, String)>, app: Data<AppState>) -> impl Responder {
+    let (key, sub) = path.into_inner();
+    let (key, sub) = (secret::digest(&key), sub.to_lowercase());
+
+    let domain = match helpers::holder_domain(&key, &app).await {
+        Ok(domain) => domain,
+        Err(error) => return error,
+    };
+
+    let records = domain.records();
+    let remaining: Vec<Record> = records.iter().filter(|record| record.name != sub).cloned().collect();
+
+    if sub == "@" || remaining.len() == records.len() {
+        return errors::error(Code::RecordNotFound, format!("{sub}.{}.{} has no records.", domain.name, domain.tld));
+    }
+
+    match app.db.update(&key, &domain.ip, &remaining, SECRET_KEY).await {
+        Ok(true) => {
+            helpers::emit(EventKind::Updated, &key, &app).await;
+            HttpResponse::Ok().finish()
+        }
+        Ok(false) => errors::domain_not_found(),
+        Err(err) => errors::internal(err),
     }
 }
 
 #[actix_web::put("/domain/{key}")]
 pub(crate) async fn update_domain(path: web::Path<String>, domain_update: web::Json<UpdateDomain>, app: Data<AppState>) -> impl Responder {
-    let key = path.into_inner();
-    let filter = doc! { "secret_key": key };
-    let update = doc! { "$set": { "ip": &domain_update.ip } };
-
-    match app.db.update_one(filter, update, None).await {
-        Ok(result) => {
-            if result.matched_count == 1 {
-                HttpResponse::Ok().json(domain_update.into_inner())
-            } else {
-                HttpResponse::NotFound().finish()
-            }
+    let key = secret::digest(&path.into_inner());
+    let UpdateDomain { ip, records } = domain_update.into_inner();
+
+    let records = match helpers::holder_domain(&key, &app).await {
+        Ok(domain) => helpers::update_records(&domain, &ip, records),
+        Err(error) => return error,
+    };
+
+    let (ip, records) = match helpers::validate_records(&ip, &records).and_then(|(ip, records)| helpers::validate_target(&ip).map(|_| (ip, records))) {
+        Ok(res) => res,
+        Err(error) => return error,
+    };
+
+    match app.db.update(&key, &ip, &records, SECRET_KEY).await {
+        Ok(true) => {
+            helpers::emit(EventKind::Updated, &key, &app).await;
+            HttpResponse::Ok().json(UpdateDomain { ip, records })
         }
-        Err(_) => HttpResponse::InternalServerError().finish(),
+        Ok(false) => errors::domain_not_found(),
+        Err(err) => errors::internal(err),
+    }
+}
+
+#[actix_web::post("/domain/{key}/rotate")]
+pub(crate) async fn rotate_key(path: web::Path<String>, app: Data<AppState>) -> impl Responder {
+    let key = secret::digest(&path.into_inner());
+    let secret_key = secret::generate(31);
+
+    if let Err(error) = helpers::holder_domain(&key, &app).await {
+        return error;
+    }
+
+    match app.db.rotate(&key, &secret::digest(&secret_key)).await {
+        Ok(true) => HttpResponse::Ok().json(SecretKey { secret_key }),
+        Ok(false) => errors::domain_not_found(),
+        Err(err) => errors::internal(err),
+    }
+}
+
+#[actix_web::get("/domain/{key}/history")]
+pub(crate) async fn key_history(path: web::Path<String>, app: Data<AppState>) -> impl Responder {
+    let key = secret::digest(&path.into_inner());
+
+    match app.db.find_by_key(&key).await {
+        Ok(Some(domain)) => helpers::domain_history(domain, false, &app).await,
+        Ok(None) => errors::domain_not_found(),
+        Err(err) => errors::internal(err),
+    }
+}
+
+#[actix_web::post("/domain/{key}/renew")]
+pub(crate) async fn renew_domain(path: web::Path<String>, app: Data<AppState>) -> impl Responder {
+    let key = secret::digest(&path.into_inner());
+
+    let domain = match app.db.find_by_key(&key).await {
+        Ok(Some(domain)) => domain,
+        Ok(None) => return errors::domain_not_found(),
+        Err(err) => return errors::internal(err),
+    };
+
+    let config = app.config.load();
+    let renew_error = |code: Code, error: String| errors::failed(code, "Failed to renew domain", error);
+
+    let (Some(lifetime), Some(expires)) = (config.tld(&domain.tld).and_then(|policy| policy.lifetime()), domain.expires) else {
+        return renew_error(Code::NotRenewable, format!("{}.{} does not expire.", domain.name, domain.tld));
+    };
+
+    let now = time::now();
+
+    if now >= expires.saturating_add(config.grace_for(&domain.tld)) {
+        return renew_error(Code::DomainExpired, "The grace period has ended, the domain is being released.".into());
+    }
+
+    // renewals extend by one period at a time, so names can not be held indefinitely in advance
+    if expires > now.saturating_add(lifetime) {
+        return renew_error(Code::AlreadyRenewed, "The domain is already renewed for the next period.".into());
+    }
+
+    let Some(expires) = expires.max(now).checked_add(lifetime) else {
+        return renew_error(Code::InvalidExpiry, "The renewed expiry is out of range.".into());
+    };
+
+    match app.db.renew(&key, expires).await {
+        Ok(true) => {
+            app.cache.forget(&domain.name, &domain.tld);
+            HttpResponse::Ok().json(Renewal { expires })
+        }
+        Ok(false) => errors::domain_not_found(),
+        Err(err) => errors::internal(err),
+    }
+}
+
+fn verification_error(code: Code, error: String) -> HttpResponse { errors::failed(code, "Failed to verify domain", error) }
+
+/// Where the token of `domain` is looked for, failing unless it points to an http(s) URL
+fn proof_url(domain: &Domain, config: &Config) -> Result<String, HttpResponse> {
+    verify::proof_url(&domain.ip, config).ok_or_else(|| {
+        verification_error(Code::NotVerifiable, format!("{}.{} points to '{}', only http(s) URLs can be verified.", domain.name, domain.tld, domain.ip))
+    })
+}
+
+/// Issue the token proving control over the target, the same one until the target changes
+#[actix_web::post("/domain/{key}/verification")]
+pub(crate) async fn start_verification(path: web::Path<String>, app: Data<AppState>) -> impl Responder {
+    let key = secret::digest(&path.into_inner());
+
+    let domain = match app.db.find_by_key(&key).await {
+        Ok(Some(domain)) => domain,
+        Ok(None) => return errors::domain_not_found(),
+        Err(err) => return errors::internal(err),
+    };
+
+    let url = match proof_url(&domain, &app.config.load_full()) {
+        Ok(url) => url,
+        Err(error) => return error,
+    };
+
+    if let Some(verification) = domain.current_verification() {
+        return HttpResponse::Ok().json(VerificationStatus::new(verification.clone(), url));
+    }
+
+    let verification = Verification {
+        token: secret::generate(32),
+        target: domain.ip.clone(),
+        verified: None,
+    };
+
+    match app.db.set_verification(&key, &verification).await {
+        Ok(true) => HttpResponse::Ok().json(VerificationStatus::new(verification, url)),
+        Ok(false) => errors::domain_not_found(),
+        Err(err) => errors::internal(err),
+    }
+}
+
+/// Fetch the token from the target and mark the domain verified once it is found
+#[actix_web::post("/domain/{key}/verification/check")]
+pub(crate) async fn check_verification(path: web::Path<String>, app: Data<AppState>) -> impl Responder {
+    let key = secret::digest(&path.into_inner());
+    let config = app.config.load_full();
+
+    let domain = match app.db.find_by_key(&key).await {
+        Ok(Some(domain)) => domain,
+        Ok(None) => return errors::domain_not_found(),
+        Err(err) => return errors::internal(err),
+    };
+
+    let url = match proof_url(&domain, &config) {
+        Ok(url) => url,
+        Err(error) => return error,
+    };
+
+    let Some(mut verification) = domain.current_verification().cloned() else {
+        return verification_error(Code::VerificationMissing, format!("Request a token for the current target of {}.{} first.", domain.name, domain.tld));
+    };
+
+    if verification.verified.is_some() {
+        return HttpResponse::Ok().json(VerificationStatus::new(verification, url));
+    }
+
+    if let Err(err) = verify::check(&url, &verification.token, &config).await {
+        return verification_error(Code::VerificationFailed, err.to_string());
+    }
+
+    verification.verified = Some(time::now());
+
+    match app.db.set_verification(&key, &verification).await {
+        Ok(true) => {
+            app.cache.forget(&domain.name, &domain.tld);
+            HttpResponse::Ok().json(VerificationStatus::new(verification, url))
+        }
+        Ok(false) => errors::domain_not_found(),
+        Err(err) => errors::internal(err),
     }
 }
 
 #[actix_web::delete("/domain/{key}")]
 pub(crate) async fn delete_domain(path: web::Path<String>, app: Data<AppState>) -> impl Responder {
-    let key = path.into_inner();
-    let filter = doc! { "secret_key": key };
-
-    match app.db.delete_one(filter, None).await {
-        Ok(result) => {
-            if result.deleted_count == 1 {
-                HttpResponse::Ok().finish()
-            } else {
-                HttpResponse::NotFound().finish()
-            }
-        }
-        Err(_) => HttpResponse::InternalServerError().finish(),
+    let key = secret::digest(&path.into_inner());
+
+    if let Err(error) = helpers::holder_domain(&key, &app).await {
+        return error;
+    }
+
+    match helpers::delete_domain(&key, &app).await {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => errors::domain_not_found(),
+        Err(err) => errors::internal(err),
     }
 }
 
 #[actix_web::post("/domain/check")]
 pub(crate) async fn check_domain(query: web::Json<DomainQuery>, app: Data<AppState>) -> impl Responder {
-    let DomainQuery { name, tld } = query.into_inner();
+    let DomainQuery { name, tld, suggest } = query.into_inner();
 
-    let result = helpers::is_domain_taken(&name, tld.as_deref(), app).await;
-    HttpResponse::Ok().json(result)
+    if let Some(tld) = &tld {
+        if let Err(violation) = naming::validate(&name, &app.config.load().naming_for(tld)) {
+            return errors::failed(Code::Naming(violation.code()), "Invalid domain name", violation.message());
+        }
+    }
+
+    match helpers::is_domain_taken(&name, tld.as_deref(), suggest, &app).await {
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(err) => errors::internal(err),
+    }
+}
+
+#[actix_web::post("/domains/resolve")]
+pub(crate) async fn resolve_domains(body: web::Json<ResolveQuery>, app: Data<AppState>) -> impl Responder {
+    let requested = body.into_inner().domains;
+
+    if requested.len() > MAX_RESOLVE {
+        return errors::failed(Code::BatchTooLarge, "Failed to resolve domains", format!("At most {MAX_RESOLVE} domains can be resolved at once."));
+    }
+
+    let config = app.config.load_full();
+    let tlds = config.tld_list();
+
+    // host, name and tld of each domain, `None` when its tld is not served
+    let parsed: Vec<Option<(String, String, String)>> = requested
+        .iter()
+        .map(|domain| {
+            let domain = domain.trim().trim_end_matches('.').to_lowercase();
+            let (rest, tld) = domain.rsplit_once('.').filter(|(_, tld)| tlds.contains(tld))?;
+            let (host, name) = rest.rsplit_once('.').unwrap_or(("@", rest));
+
+            Some((host.to_string(), naming::to_ascii(name), tld.to_string()))
+        })
+        .collect();
+
+    let names: Vec<(String, String)> = parsed.iter().flatten().map(|(_, name, tld)| (name.clone(), tld.clone())).collect();
+    let domains = match app.db.find_many(&names).await {
+        Ok(domains) => domains,
+        Err(err) => return errors::internal(err),
+    };
+
+    let now = time::now();
+    let resolved = requested.into_iter().zip(parsed).map(|(requested, parsed)| {
+        let Some((host, name, tld)) = parsed else {
+            return Resolved { domain: requested, code: Some(Code::UnknownTld), found: None };
+        };
+
+        let (code, found) = match domains.iter().find(|domain| domain.name == name && domain.tld == tld) {
+            None => (Some(Code::DomainNotFound), None),
+            Some(domain) if domain.suspended.is_some() => (Some(Code::DomainSuspended), None),
+            Some(domain) if domain.is_expired(now, &config) => (Some(Code::DomainExpired), None),
+            Some(domain) if host == "@" => (None, Some(ResponseDomain::from(domain.clone()))),
+            Some(domain) => match domain.resolve(&host) {
+                records if records.is_empty() => (Some(Code::RecordNotFound), None),
+                records => (None, Some(subdomain_response(domain.clone(), &host, records))),
+            },
+        };
+
+        Resolved { domain: requested, code, found }
+    });
+
+    HttpResponse::Ok().json(resolved.collect::<Vec<_>>())
 }
 
 #[actix_web::get("/domains")]
@@ -160,48 +603,94 @@ pub(crate) async fn get_domains(query: web::Query<PaginationParams>, app: Data<A
     let limit = query.page_size.unwrap_or(15);
 
     if page == 0 || limit == 0 {
-        return HttpResponse::BadRequest().json(Error {
-            msg: "page_size or page must be greater than 0",
-            error: "Invalid pagination parameters".into(),
-        });
+        return errors::failed(Code::InvalidPagination, "Invalid pagination parameters", "page_size or page must be greater than 0");
     }
 
     if limit > 100 {
-        return HttpResponse::BadRequest().json(Error {
-            msg: "page_size must be greater than 0 and less than or equal to 100",
-            error: "Invalid pagination parameters".into(),
-        });
+        return errors::failed(Code::InvalidPagination, "Invalid pagination parameters", "page_size must be greater than 0 and less than or equal to 100");
     }
 
-    let skip = (page - 1) * limit;
-    let find_options = FindOptions::builder().skip(Some(skip as u64)).limit(Some(limit as i64)).build();
+    let after = match query.cursor.as_deref().map(str::parse::<Cursor>).transpose() {
+        Ok(after) => after,
+        Err(_) => return errors::failed(Code::InvalidPagination, "Invalid pagination parameters", "cursor must be the `next` value of a previous page"),
+    };
+
+    let term = |value: &Option<String>| value.as_ref().map(|value| value.trim().to_lowercase()).filter(|value| !value.is_empty());
 
-    let cursor = match app.db.find(None, find_options).await {
+    // a cursor replaces page numbers, so pages stay stable while domains are added
+    let search = Search {
+        tld: term(&query.tld),
+        prefix: term(&query.prefix),
+        contains: term(&query.search),
+        sort: query.sort,
+        order: query.order,
+        skip: if after.is_some() { 0 } else { (page as u64 - 1) * limit as u64 },
+        limit: limit as u64 + 1,
+        after,
+    };
+
+    let (mut domains, total) = match app.db.search(&search).await {
         Ok(res) => res,
-        Err(err) => {
-            return HttpResponse::InternalServerError().json(Error {
-                msg: "Failed to fetch cursor",
-                error: err.to_string(),
-            })
+        Err(err) => return errors::internal(err),
+    };
+
+    let next = match domains.len() > limit as usize {
+        true => {
+            domains.truncate(limit as usize);
+            domains.last().map(|domain| Cursor::of(domain).to_string())
         }
+        false => None,
     };
 
-    let domains: Vec<ResponseDomain> = cursor
-        .filter_map(|result| async {
-            match result {
-                Ok(domain) => Some(ResponseDomain {
-                    tld: domain.tld,
-                    name: domain.name,
-                    ip: domain.ip,
-                }),
-                Err(_) => None,
-            }
-        })
-        .collect()
-        .await;
+    HttpResponse::Ok().json(PaginationResponse {
+        domains: domains.into_iter().map(ResponseDomain::from).collect(),
+        page: search.after.is_none().then_some(page),
+        limit,
+        total,
+        next,
+    })
+}
+
+#[actix_web::get("/events")]
+pub(crate) async fn event_stream(req: HttpRequest, query: web::Query<EventQuery>, app: Data<AppState>) -> impl Responder {
+    let last_event: Option<u64> = req.headers().get("last-event-id").and_then(|id| id.to_str().ok()?.trim().parse().ok());
+    let after = last_event.or(query.after);
 
-    HttpResponse::Ok().json(PaginationResponse { domains, page, limit })
+    // no event can follow the largest id
+    if let Some(after) = after.filter(|after| after.checked_add(1).is_none()) {
+        return errors::error(Code::InvalidQuery, format!("No event can follow {after}."));
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("cache-control", "no-cache"))
+        .streaming(events::stream(app.events.clone(), after))
 }
 
 #[actix_web::get("/tlds")]
-pub(crate) async fn get_tlds(app: Data<AppState>) -> impl Responder { HttpResponse::Ok().json(&*app.config.tld_list()) }
+pub(crate) async fn get_tlds(query: web::Query<TldQuery>, app: Data<AppState>, req: HttpRequest) -> impl Responder {
+    let config = app.config.load_full();
+    let max_age = config.settings.cache.max_age;
+
+    // built from the config without touching storage, so only tagged for clients to cache
+    if !query.details {
+        return helpers::conditional(&req, &config.tld_list(), max_age);
+    }
+
+    let tlds: Vec<TldInfo> = config
+        .tld_list()
+        .into_iter()
+        .filter_map(|name| {
+            let policy = config.tld(name)?;
+            Some(TldInfo {
+                name: name.to_string(),
+                policy: Tld {
+                    naming: Some(config.naming_for(name)),
+                    ..policy
+                },
+            })
+        })
+        .collect();
+
+    helpers::conditional(&req, &tlds, max_age)
+}
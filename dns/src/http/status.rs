@@ -0,0 +1,103 @@
+use super::AppState;
+use crate::{kv, metrics::METRICS};
+use serde::Serialize;
+use std::{collections::BTreeMap, time::Duration};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::Method,
+    web::Data,
+    Error, HttpResponse, Responder,
+};
+
+use futures::future::LocalBoxFuture;
+use tokio::time::{timeout, Instant};
+
+/// How long the storage backend gets to answer a health check
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    /// Outcome of each check, `ok`, `unavailable` or the time it went unanswered
+    checks: BTreeMap<&'static str, String>,
+}
+
+async fn health(app: &AppState) -> Health {
+    let storage = match timeout(PING_TIMEOUT, app.db.ping()).await {
+        Ok(Ok(_)) => "ok".to_string(),
+        Ok(Err(err)) => {
+            log::error!("Storage health check failed: {err}");
+            "unavailable".to_string()
+        }
+        Err(_) => format!("no answer within {}s", PING_TIMEOUT.as_secs()),
+    };
+
+    // backend errors can carry hosts and credentials, they are only logged
+    let keys = match kv::ping(&app.keys) {
+        Ok(_) => "ok".to_string(),
+        Err(err) => {
+            log::error!("Key database health check failed: {err}");
+            "unavailable".to_string()
+        }
+    };
+
+    let checks = BTreeMap::from([("storage", storage), ("keys", keys)]);
+    let status = match checks.values().all(|check| check == "ok") {
+        true => "ok",
+        false => "failing",
+    };
+
+    Health { status, checks }
+}
+
+/// Liveness, answers as long as the daemon does and reports the checks of `/readyz`
+#[actix_web::get("/healthz")]
+pub(crate) async fn healthz(app: Data<AppState>) -> impl Responder { HttpResponse::Ok().json(health(&app).await) }
+
+/// Readiness, fails when the storage backend or the key database does
+#[actix_web::get("/readyz")]
+pub(crate) async fn readyz(app: Data<AppState>) -> impl Responder {
+    let health = health(&app).await;
+
+    match health.status {
+        "ok" => HttpResponse::Ok().json(health),
+        _ => HttpResponse::ServiceUnavailable().json(health),
+    }
+}
+
+#[actix_web::get("/metrics")]
+pub(crate) async fn metrics() -> impl Responder { HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(METRICS.render()) }
+
+/// Count and time every request by its route pattern
+pub(crate) fn track<S, B>(req: ServiceRequest, srv: &S) -> LocalBoxFuture<'static, Result<ServiceResponse<B>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    // unmatched paths share a label so scans can not grow the metrics
+    let route = match req.match_pattern() {
+        Some(pattern) => pattern.strip_prefix("/v1").unwrap_or(&pattern).to_string(),
+        None => "unmatched".to_string(),
+    };
+
+    // so are methods outside the standard ones
+    let method = match *req.method() {
+        Method::GET | Method::HEAD | Method::POST | Method::PUT | Method::PATCH | Method::DELETE | Method::OPTIONS => req.method().as_str(),
+        _ => "other",
+    };
+
+    let (method, start) = (method.to_string(), Instant::now());
+    let future = srv.call(req);
+
+    Box::pin(async move {
+        let response = future.await?;
+
+        METRICS.http_requests.with_label_values(&[&method, &route, response.status().as_str()]).inc();
+        METRICS.http_duration.with_label_values(&[&method, &route]).observe(start.elapsed().as_secs_f64());
+
+        Ok(response)
+    })
+}
@@ -1,70 +1,216 @@
+use crate::{secret, time};
 use anyhow::{anyhow, Error};
 use prettytable::{format, row, Table};
-use std::{collections::HashMap, fs::File, str::from_utf8};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, str::from_utf8};
 
-pub fn get(path: &String, key: &String) -> Result<String, Error> {
-    log::debug!("{}", path);
-    let db = sled::open(&path)?;
-    let value = db.get(&key)?;
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    /// Register domains through `/registry/domain`
+    Create,
+    /// Update any domain without its secret key
+    UpdateAny,
+    /// Delete any domain without its secret key
+    DeleteAny,
+    /// Read full domain details
+    ReadAdmin,
+    /// Suspend, reinstate, delete and reserve domains through `/admin`
+    Moderate,
+    /// Manage API keys and owners through `/admin`, implies `moderate`
+    Admin,
+}
 
-    match value {
-        Some(value) => {
-            let utf8 = from_utf8(&value)?;
-            Ok(String::from(utf8))
+impl Scope {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scope::Create => "create",
+            Scope::UpdateAny => "update-any",
+            Scope::DeleteAny => "delete-any",
+            Scope::ReadAdmin => "read-admin",
+            Scope::Moderate => "moderate",
+            Scope::Admin => "admin",
         }
-        None => Err(anyhow!("Key does not exist in {path}")),
     }
 }
 
-pub fn set(path: &String, key: &String, value: &String) -> Result<(), Error> {
-    let db = sled::open(&path)?;
-    db.insert(&key, sled::IVec::from(macros_rs::fmt::str!(value.clone())))?;
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiKey {
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub hash: String,
+    pub scopes: Vec<Scope>,
+    #[serde(default)]
+    pub description: String,
+    pub created: u64,
+    pub expires: Option<u64>,
+    pub last_used: Option<u64>,
+}
+
+impl ApiKey {
+    pub fn new(hash: String, scopes: Vec<Scope>, description: String, expires: Option<u64>) -> Self {
+        Self {
+            hash,
+            scopes,
+            description,
+            expires,
+            created: time::now(),
+            last_used: None,
+        }
+    }
+
+    /// Keys written before scopes existed only hold the secret and could only create domains
+    fn parse(value: &[u8]) -> Result<Self, Error> {
+        match serde_json::from_slice(value) {
+            Ok(key) => Ok(key),
+            Err(_) => Ok(Self {
+                hash: from_utf8(value)?.to_string(),
+                scopes: vec![Scope::Create],
+                description: String::new(),
+                created: 0,
+                expires: None,
+                last_used: None,
+            }),
+        }
+    }
+
+    /// Creation time, unknown for keys written before it was recorded
+    pub fn created(&self) -> Option<u64> { Some(self.created).filter(|created| *created > 0) }
+
+    pub fn is_expired(&self) -> bool { self.expires.is_some_and(|expires| expires <= time::now()) }
+    pub fn allows(&self, scope: Scope) -> bool { self.scopes.contains(&scope) || (scope == Scope::Moderate && self.scopes.contains(&Scope::Admin)) }
+
+    pub fn scope_list(&self) -> String {
+        let scopes: Vec<&str> = self.scopes.iter().map(Scope::name).collect();
+        scopes.join(", ")
+    }
+}
+
+pub fn open(path: &str) -> Result<sled::Db, Error> {
+    log::debug!("{}", path);
+    sled::open(path).map_err(|err| anyhow!("Cannot open key database {path}, is the daemon running? ({err})"))
+}
+
+pub fn get(db: &sled::Db, name: &str) -> Result<ApiKey, Error> {
+    match db.get(name)? {
+        Some(value) => ApiKey::parse(&value),
+        None => Err(anyhow!("Key {name} does not exist")),
+    }
+}
+
+pub fn set(db: &sled::Db, name: &str, key: &ApiKey) -> Result<(), Error> {
+    db.insert(name, serde_json::to_vec(key)?)?;
     db.flush()?;
 
     Ok(())
 }
 
-pub fn remove(path: &String, key: &String) -> Result<(), Error> {
-    let db = sled::open(&path)?;
-    db.remove(&key)?;
+/// Unix time `days` from now, `None` when it does not fit
+pub fn expiry_after(days: u64) -> Option<u64> { days.checked_mul(86400)?.checked_add(time::now()) }
+
+/// Generate a new key, returns the plaintext secret which is only stored hashed
+pub fn create(db: &sled::Db, name: &str, scopes: Vec<Scope>, expires: Option<u64>, description: String) -> Result<(String, ApiKey), Error> {
+    let secret = secret::generate(60);
+    let hash = secret::hash(&secret).map_err(|err| anyhow!("Failed to hash key: {err}"))?;
+    let expires = expires.map(|days| expiry_after(days).ok_or_else(|| anyhow!("An expiry of {days} days is out of range"))).transpose()?;
+    let key = ApiKey::new(hash, scopes, description, expires);
+
+    set(db, name, &key)?;
+    Ok((secret, key))
+}
+
+/// Remove a key, returns false if it did not exist
+pub fn remove(db: &sled::Db, name: &str) -> Result<bool, Error> {
+    let removed = db.remove(name)?.is_some();
     db.flush()?;
 
+    Ok(removed)
+}
+
+/// Fail when the key database can not be read
+pub fn ping(db: &sled::Db) -> Result<(), Error> {
+    db.size_on_disk()?;
+    db.first()?;
+
     Ok(())
 }
 
-pub fn list(path: &String, silent: bool) -> Result<(), Error> {
-    let db = sled::open(&path)?;
+/// Verify `secret` for the key `name` and record its use
+pub fn authorize(db: &sled::Db, name: &str, secret: &str) -> Result<Option<ApiKey>, Error> {
+    let Some(value) = db.get(name)? else { return Ok(None) };
+    let mut key = ApiKey::parse(&value)?;
+
+    if !secret::verify(secret, &key.hash) {
+        return Ok(None);
+    }
+
+    // only recorded while the key is unchanged, a key removed or replaced meanwhile stays that way
+    key.last_used = Some(time::now());
+    let _ = db.compare_and_swap(name, Some(value), Some(serde_json::to_vec(&key)?))?;
+
+    Ok(Some(key))
+}
+
+/// Every key by name, without their hashes
+pub fn all(db: &sled::Db) -> Result<Vec<(String, ApiKey)>, Error> {
+    Ok(rows(db)?.into_iter().map(|(name, key)| (name, ApiKey { hash: String::new(), ..key })).collect())
+}
+
+fn rows(db: &sled::Db) -> Result<Vec<(String, ApiKey)>, Error> {
+    db.iter()
+        .map(|row| {
+            let (name, value) = row?;
+            Ok((String::from(from_utf8(&name)?), ApiKey::parse(&value)?))
+        })
+        .collect()
+}
+
+fn table(db: &sled::Db) -> Result<Table, Error> {
     let mut table = Table::new();
-    let mut store: HashMap<String, String> = HashMap::new();
+    table.set_titles(row!["Key", "Scopes", "Created", "Expires", "Last used", "Description"]);
+
+    for (name, key) in rows(db)? {
+        let expires = match key.is_expired() {
+            true => format!("{} (expired)", time::format(key.expires)),
+            false => time::format(key.expires),
+        };
+
+        table.add_row(row![name, key.scope_list(), time::format(key.created()), expires, time::format(key.last_used), key.description]);
+    }
 
-    table.set_titles(row!["Key", "Value"]);
-    table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+    Ok(table)
+}
 
+pub fn list(db: &sled::Db, silent: bool) -> Result<(), Error> {
     if silent {
-        for row in db.iter() {
-            let (key, val) = row.clone()?;
-            store.insert(String::from(from_utf8(&key.to_vec())?), String::from(from_utf8(&val.to_vec())?));
-        }
-        Ok(println!("{:?}", store))
+        let store: HashMap<String, Vec<Scope>> = rows(db)?.into_iter().map(|(name, key)| (name, key.scopes)).collect();
+        println!("{:?}", store);
     } else {
-        for row in db.iter() {
-            let (key, val) = row.expect("Could not read row");
-            table.add_row(row![String::from_utf8(key.to_vec())?, String::from_utf8(val.to_vec())?]);
-        }
-        Ok(table.printstd())
+        let mut table = table(db)?;
+        table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+        table.printstd();
     }
+
+    Ok(())
 }
 
-pub fn save(path: &String, filename: &String) -> Result<(), Error> {
-    let db = sled::open(path)?;
-    let out = File::create(filename)?;
-    let mut table = Table::new();
+/// Write every key as CSV, without their hashes
+pub fn save<W: std::io::Write>(db: &sled::Db, out: W) -> Result<(), Error> {
+    table(db)?.to_csv(out)?;
+    Ok(())
+}
+
+/// Hash every secret that is still stored in plaintext
+pub fn migrate(db: &sled::Db) -> Result<u64, Error> {
+    let mut migrated = 0;
 
-    for row in db.iter() {
-        let (key, val) = row.expect("Could not read row");
-        table.add_row(row![String::from_utf8(key.to_vec())?, String::from_utf8(val.to_vec())?]);
+    for (name, mut key) in rows(db)? {
+        if !secret::is_hash(&key.hash) {
+            key.hash = secret::hash(&key.hash).map_err(|err| anyhow!("{err}"))?;
+            db.insert(name, serde_json::to_vec(&key)?)?;
+            migrated += 1;
+        }
     }
 
-    table.to_csv(out)?;
-    Ok(())
+    db.flush()?;
+    Ok(migrated)
 }
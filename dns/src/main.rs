@@ -1,8 +1,19 @@
+mod cache;
 mod cli;
 mod config;
+mod events;
 mod http;
 mod kv;
+mod metrics;
+mod naming;
+mod nameserver;
+mod net;
+mod reclaim;
 mod secret;
+mod store;
+mod time;
+mod verify;
+mod zone;
 
 use clap::{Parser, Subcommand};
 use clap_verbosity_flag::{LogLevel, Verbosity};
@@ -29,11 +40,49 @@ struct Cli {
 enum Commands {
     /// Start the daemon
     Start,
+    /// Hash secrets stored in plaintext by older versions
+    Migrate,
     /// Manage API keys
     Key {
         #[command(subcommand)]
         command: Key,
     },
+    /// Back up and restore the domain registry
+    Zone {
+        #[command(subcommand)]
+        command: Zone,
+    },
+}
+
+#[derive(Subcommand)]
+enum Zone {
+    /// Export all domains
+    #[command(visible_alias = "save")]
+    Export {
+        /// Exported file name, or directory of `<tld>.zone` files for bind
+        path: String,
+        /// Format to write
+        #[arg(short, long, value_enum, default_value_t = zone::Format::Jsonl)]
+        format: zone::Format,
+        /// Only export domains on this tld
+        #[arg(short, long)]
+        tld: Option<String>,
+    },
+    /// Import domains from an export
+    #[command(visible_alias = "load")]
+    Import {
+        /// File to import, or directory of `*.zone` files for bind
+        path: String,
+        /// Format to read
+        #[arg(short, long, value_enum, default_value_t = zone::Format::Jsonl)]
+        format: zone::Format,
+        /// What to do with domains that are already registered
+        #[arg(long = "on-conflict", value_enum, default_value_t = zone::Conflict::Skip)]
+        conflict: zone::Conflict,
+        /// Show what would be imported without changing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
 }
 
 #[derive(Subcommand)]
@@ -58,6 +107,15 @@ enum Key {
     Create {
         /// Key name
         name: String,
+        /// Permissions granted to the key
+        #[arg(short, long = "scope", value_enum, default_values_t = [kv::Scope::Create])]
+        scopes: Vec<kv::Scope>,
+        /// Days until the key expires
+        #[arg(short, long)]
+        expires: Option<u64>,
+        /// What the key is used for
+        #[arg(short, long, default_value_t = String::new())]
+        description: String,
     },
     /// Remove API key
     #[command(visible_alias = "rm")]
@@ -76,7 +134,7 @@ fn main() {
 
     if !file_exists!(&cli.config) {
         Config::new().set_path(&cli.config).write();
-        log::warn!("Written initial config, please add MongoDB details");
+        log::warn!("Written initial config, please add storage details");
         std::process::exit(1);
     }
 
@@ -86,12 +144,17 @@ fn main() {
                 log::error!("Failed to start server: {err}")
             }
         }
+        Commands::Migrate => cli::migrate(&cli),
         Commands::Key { command } => match command {
             Key::List => cli::list(&cli),
             Key::Info { name } => cli::info(&cli, name),
-            Key::Create { name } => cli::create(&cli, name),
+            Key::Create { name, scopes, expires, description } => cli::create(&cli, name, scopes, *expires, description),
             Key::Delete { name } => cli::remove(&cli, name),
             Key::Export { filename } => cli::export(&cli, filename),
         },
+        Commands::Zone { command } => match command {
+            Zone::Export { path, format, tld } => cli::zone_export(&cli, path, *format, tld.as_deref()),
+            Zone::Import { path, format, conflict, dry_run } => cli::zone_import(&cli, path, *format, *conflict, *dry_run),
+        },
     };
 }
@@ -0,0 +1,70 @@
+use mongodb::event::command::{CommandEventHandler, CommandFailedEvent, CommandSucceededEvent};
+use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder};
+use std::{sync::LazyLock, time::Duration};
+
+/// Every metric of the daemon, served in the Prometheus text format at `/metrics`
+pub(crate) struct Metrics {
+    registry: Registry,
+    /// DNS queries by tld and response code
+    pub(crate) dns_queries: IntCounterVec,
+    /// HTTP requests by route pattern and status
+    pub(crate) http_requests: IntCounterVec,
+    pub(crate) http_duration: HistogramVec,
+    /// Refused domain registrations by error code
+    pub(crate) rejections: IntCounterVec,
+    /// Requests refused by a rate limit, by route
+    pub(crate) ratelimited: IntCounterVec,
+    /// Domain lookups by whether the cache answered them
+    pub(crate) cache: IntCounterVec,
+    /// Domain lookups over HTTP by tld
+    pub(crate) lookups: IntCounterVec,
+    /// MongoDB commands by name and outcome
+    pub(crate) store_duration: HistogramVec,
+}
+
+pub(crate) static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+fn counter(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    let counter = IntCounterVec::new(Opts::new(name, help), labels).expect("valid counter");
+    registry.register(Box::new(counter.clone())).expect("unique metric name");
+    counter
+}
+
+fn histogram(registry: &Registry, name: &str, help: &str, labels: &[&str], buckets: Vec<f64>) -> HistogramVec {
+    let histogram = HistogramVec::new(HistogramOpts::new(name, help).buckets(buckets), labels).expect("valid histogram");
+    registry.register(Box::new(histogram.clone())).expect("unique metric name");
+    histogram
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let buckets = vec![0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+        Self {
+            dns_queries: counter(&registry, "webx_dns_queries_total", "DNS queries answered, by tld and response code", &["tld", "rcode"]),
+            http_requests: counter(&registry, "webx_http_requests_total", "HTTP requests, by method, route and status", &["method", "route", "status"]),
+            http_duration: histogram(&registry, "webx_http_request_duration_seconds", "Time to answer HTTP requests, by method and route", &["method", "route"], buckets.clone()),
+            rejections: counter(&registry, "webx_domain_rejections_total", "Refused domain registrations, by error code", &["code"]),
+            ratelimited: counter(&registry, "webx_ratelimited_total", "Requests refused by a rate limit, by route", &["route"]),
+            cache: counter(&registry, "webx_cache_lookups_total", "Domain lookups, by whether the cache answered them", &["result"]),
+            lookups: counter(&registry, "webx_domain_lookups_total", "Domain lookups over HTTP, by tld", &["tld"]),
+            store_duration: histogram(&registry, "webx_mongo_command_duration_seconds", "Time taken by MongoDB commands, by command and outcome", &["command", "outcome"], buckets),
+            registry,
+        }
+    }
+
+    /// Current values in the Prometheus text format
+    pub(crate) fn render(&self) -> String { TextEncoder::new().encode_to_string(&self.registry.gather()).unwrap_or_default() }
+
+    fn command(&self, command: &str, outcome: &str, duration: Duration) { self.store_duration.with_label_values(&[command, outcome]).observe(duration.as_secs_f64()) }
+}
+
+/// Times the commands sent to MongoDB
+pub(crate) struct CommandTimer;
+
+impl CommandEventHandler for CommandTimer {
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) { METRICS.command(&event.command_name, "ok", event.duration) }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) { METRICS.command(&event.command_name, "error", event.duration) }
+}
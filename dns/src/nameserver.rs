@@ -0,0 +1,338 @@
+use crate::{
+    config::{Config, SharedConfig},
+    http::{Record as DomainRecord, RecordKind},
+    metrics::METRICS,
+    store::DomainStore,
+    time,
+    zone::SOA_TIMERS,
+};
+use anyhow::{anyhow, Error};
+use std::{sync::Arc, time::Duration};
+
+use hickory_proto::{
+    op::{Message, MessageType, OpCode, ResponseCode},
+    rr::{
+        rdata::{A, AAAA, NS, NULL, SOA, TXT},
+        Name, RData, Record, RecordType,
+    },
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::Semaphore,
+    time::timeout,
+};
+
+/// RR type code of URI records (RFC 7553), not natively known by hickory
+const URI: u16 = 256;
+const UDP_SIZE: usize = 512;
+
+/// UDP queries answered at once, further packets wait in the socket buffer
+const MAX_UDP_QUERIES: usize = 1024;
+/// TCP connections served at once, further ones wait in the accept queue
+const MAX_TCP_CONNECTIONS: usize = 256;
+/// How long a TCP connection may sit idle or take to send a query or read an answer
+const TCP_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) struct Nameserver {
+    config: SharedConfig,
+    db: Arc<dyn DomainStore>,
+}
+
+impl Nameserver {
+    pub fn new(config: SharedConfig, db: Arc<dyn DomainStore>) -> Self { Self { config, db } }
+
+    pub async fn start(self, config: &Config) -> Result<(), Error> {
+        let address = config.get_dns_address();
+        let udp = UdpSocket::bind(&address).await?;
+        let tcp = TcpListener::bind(&address).await?;
+        let server = Arc::new(self);
+
+        tokio::spawn(server.clone().serve_udp(udp));
+        tokio::spawn(server.serve_tcp(tcp));
+
+        log::info!("DNS listening on {address} (udp/tcp)");
+        Ok(())
+    }
+
+    async fn serve_udp(self: Arc<Self>, socket: UdpSocket) {
+        let (socket, permits) = (Arc::new(socket), Arc::new(Semaphore::new(MAX_UDP_QUERIES)));
+        let mut buf = [0u8; 4096];
+
+        loop {
+            let Ok(permit) = permits.clone().acquire_owned().await else { return };
+            let (len, peer) = match socket.recv_from(&mut buf).await {
+                Ok(res) => res,
+                Err(err) => {
+                    log::warn!("DNS udp receive failed: {err}");
+                    continue;
+                }
+            };
+
+            let (server, socket, packet) = (self.clone(), socket.clone(), buf[..len].to_vec());
+            tokio::spawn(async move {
+                if let Some(response) = server.handle(&packet, Some(UDP_SIZE)).await {
+                    if let Err(err) = socket.send_to(&response, peer).await {
+                        log::debug!("DNS udp reply to {peer} failed: {err}");
+                    }
+                }
+                drop(permit);
+            });
+        }
+    }
+
+    async fn serve_tcp(self: Arc<Self>, listener: TcpListener) {
+        let permits = Arc::new(Semaphore::new(MAX_TCP_CONNECTIONS));
+
+        loop {
+            let Ok(permit) = permits.clone().acquire_owned().await else { return };
+
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let server = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = server.connection(stream).await {
+                            log::debug!("DNS tcp connection from {peer} closed: {err}");
+                        }
+                        drop(permit);
+                    });
+                }
+                Err(err) => log::warn!("DNS tcp accept failed: {err}"),
+            }
+        }
+    }
+
+    async fn connection(&self, mut stream: TcpStream) -> Result<(), Error> {
+        let timed_out = |_| anyhow!("no progress within {}s", TCP_TIMEOUT.as_secs());
+
+        loop {
+            // idle connections are closed like ones the client ended
+            let len = match timeout(TCP_TIMEOUT, stream.read_u16()).await {
+                Ok(Ok(len)) => len as usize,
+                _ => return Ok(()),
+            };
+
+            let mut packet = vec![0u8; len];
+            timeout(TCP_TIMEOUT, stream.read_exact(&mut packet)).await.map_err(timed_out)??;
+
+            if let Some(response) = self.handle(&packet, None).await {
+                let mut framed = (response.len() as u16).to_be_bytes().to_vec();
+                framed.extend(response);
+                timeout(TCP_TIMEOUT, stream.write_all(&framed)).await.map_err(timed_out)??;
+            }
+        }
+    }
+
+    /// Answer a raw query, truncating to `limit` bytes when given
+    async fn handle(&self, packet: &[u8], limit: Option<usize>) -> Option<Vec<u8>> {
+        let request = Message::from_vec(packet).ok()?;
+
+        if request.message_type() != MessageType::Query {
+            return None;
+        }
+
+        let mut response = self.answer(&request).await;
+        let mut bytes = response.to_vec().ok()?;
+
+        if let Some(limit) = limit {
+            if bytes.len() > limit {
+                response.take_answers();
+                response.set_truncated(true);
+                bytes = response.to_vec().ok()?;
+            }
+        }
+
+        Some(bytes)
+    }
+
+    async fn answer(&self, request: &Message) -> Message {
+        let mut response = Message::new();
+
+        response
+            .set_id(request.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(request.op_code())
+            .set_recursion_desired(request.recursion_desired())
+            .set_recursion_available(false);
+
+        if request.op_code() != OpCode::Query || request.queries().len() != 1 {
+            response.set_response_code(ResponseCode::NotImp);
+            return counted(response, "-");
+        }
+
+        let query = &request.queries()[0];
+        response.add_query(query.clone());
+
+        let qname = query.name().to_lowercase();
+        let labels: Vec<String> = qname.iter().map(|label| String::from_utf8_lossy(label).into_owned()).collect();
+
+        let config = self.config.load_full();
+        let Some(tld) = labels.last().filter(|tld| config.tld_list().contains(&tld.as_str())) else {
+            response.set_response_code(ResponseCode::Refused);
+            return counted(response, "-");
+        };
+
+        response.set_authoritative(true);
+        let zone = Name::from_ascii(format!("{tld}.")).ok();
+        let soa = zone.as_ref().and_then(|zone| soa(zone, &config));
+
+        // the tld itself only carries its SOA and NS records
+        if labels.len() == 1 {
+            let answers: Vec<Record> = [soa.clone(), zone.as_ref().and_then(|zone| ns(zone, &config))]
+                .into_iter()
+                .flatten()
+                .filter(|record| query.query_type() == RecordType::ANY || record.record_type() == query.query_type())
+                .collect();
+
+            match answers.is_empty() {
+                true => response.add_name_servers(soa),
+                false => response.add_answers(answers),
+            };
+            return counted(response, tld);
+        }
+
+        let name = &labels[labels.len() - 2];
+        let host = match labels.len() {
+            2 => "@".to_string(),
+            len => labels[..len - 2].join("."),
+        };
+
+        match self.db.find(name, tld).await {
+            Ok(Some(domain)) if domain.suspended.is_none() && !domain.is_expired(time::now(), &config) => {
+                let records = domain.resolve(&host);
+                let answers = records_for(&records, query.name(), query.query_type(), config.server.dns.ttl);
+
+                // negative answers carry the SOA of the tld so resolvers can cache them (RFC 2308)
+                match (records.is_empty() && host != "@", answers.is_empty()) {
+                    (true, _) => response.set_response_code(ResponseCode::NXDomain).add_name_servers(soa),
+                    (false, true) => response.add_name_servers(soa),
+                    (false, false) => response.add_answers(answers),
+                };
+            }
+            Ok(_) => {
+                response.set_response_code(ResponseCode::NXDomain).add_name_servers(soa);
+            }
+            Err(err) => {
+                log::error!("DNS lookup for {qname} failed: {err}");
+                response.set_authoritative(false).set_response_code(ResponseCode::ServFail);
+            }
+        }
+
+        counted(response, tld)
+    }
+}
+
+/// Count an answered query, unknown tlds share the `-` label
+fn counted(response: Message, tld: &str) -> Message {
+    METRICS.dns_queries.with_label_values(&[tld, &format!("{:?}", response.response_code()).to_lowercase()]).inc();
+    response
+}
+
+/// SOA of the `zone` of a tld. The registry keeps no zone version, so the serial is the current time
+fn soa(zone: &Name, config: &Config) -> Option<Record> {
+    let (dns, (refresh, retry, expire)) = (&config.server.dns, SOA_TIMERS);
+    let soa = SOA::new(host(&dns.nameserver)?, host(&dns.hostmaster)?, time::now() as u32, refresh as i32, retry as i32, expire as i32, dns.ttl);
+
+    Some(Record::from_rdata(zone.clone(), dns.ttl, RData::SOA(soa)))
+}
+
+fn ns(zone: &Name, config: &Config) -> Option<Record> { Some(Record::from_rdata(zone.clone(), config.server.dns.ttl, RData::NS(NS(host(&config.server.dns.nameserver)?)))) }
+
+/// Absolute name of a configured host
+fn host(name: &str) -> Option<Name> { Name::from_ascii(format!("{}.", name.trim_end_matches('.'))).ok() }
+
+/// Answers for `query_type` out of the domain records, `ttl` applies to records without one
+fn records_for(records: &[DomainRecord], name: &Name, query_type: RecordType, ttl: u32) -> Vec<Record> {
+    let any = query_type == RecordType::ANY;
+
+    records
+        .iter()
+        .filter_map(|record| {
+            let rdata = match record.kind {
+                RecordKind::A if any || query_type == RecordType::A => RData::A(A(record.value.parse().ok()?)),
+                RecordKind::Aaaa if any || query_type == RecordType::AAAA => RData::AAAA(AAAA(record.value.parse().ok()?)),
+                RecordKind::Txt | RecordKind::Url if any || query_type == RecordType::TXT => txt(&record.value),
+                RecordKind::Url if query_type == RecordType::Unknown(URI) => uri(&record.value),
+                _ => return None,
+            };
+
+            Some(Record::from_rdata(name.clone(), record.ttl.unwrap_or(ttl), rdata))
+        })
+        .collect()
+}
+
+/// Build TXT rdata, splitting values into 255 byte character-strings
+fn txt(value: &str) -> RData { RData::TXT(TXT::from_bytes(value.as_bytes().chunks(255).collect())) }
+
+/// Build URI rdata: priority, weight and the target octets
+fn uri(target: &str) -> RData {
+    let mut rdata = Vec::with_capacity(4 + target.len());
+    rdata.extend_from_slice(&10u16.to_be_bytes());
+    rdata.extend_from_slice(&1u16.to_be_bytes());
+    rdata.extend_from_slice(target.as_bytes());
+
+    RData::Unknown {
+        code: RecordType::Unknown(URI),
+        rdata: NULL::with(rdata),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::EmbeddedStore;
+    use arc_swap::ArcSwap;
+    use hickory_proto::op::Query;
+
+    async fn nameserver(name: &str) -> Nameserver {
+        let path = std::env::temp_dir().join(format!("webx-nameserver-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+
+        let db = EmbeddedStore::open(&path.to_string_lossy().to_string()).unwrap();
+        db.insert(&serde_json::from_value(serde_json::json!({ "name": "example", "tld": "dev", "ip": "1.2.3.4", "secret_key": "a" })).unwrap()).await.unwrap();
+
+        Nameserver::new(Arc::new(ArcSwap::from_pointee(Config::new())), Arc::new(db))
+    }
+
+    async fn ask(server: &Nameserver, name: &str, query_type: RecordType) -> Message {
+        let mut request = Message::new();
+        request.add_query(Query::query(Name::from_ascii(name).unwrap(), query_type));
+        server.answer(&request).await
+    }
+
+    fn authority(response: &Message) -> Vec<RecordType> { response.name_servers().iter().map(Record::record_type).collect() }
+
+    #[tokio::test]
+    async fn negative_answers_carry_the_soa() {
+        let server = nameserver("negative").await;
+
+        let found = ask(&server, "example.dev.", RecordType::A).await;
+        assert_eq!((found.response_code(), found.answers().len()), (ResponseCode::NoError, 1));
+        assert!(found.name_servers().is_empty());
+
+        for (name, query_type, code) in [
+            ("missing.dev.", RecordType::A, ResponseCode::NXDomain),
+            ("www.example.dev.", RecordType::A, ResponseCode::NXDomain),
+            ("example.dev.", RecordType::AAAA, ResponseCode::NoError),
+            ("dev.", RecordType::A, ResponseCode::NoError),
+        ] {
+            let response = ask(&server, name, query_type).await;
+            assert_eq!(response.response_code(), code, "{name}");
+            assert!(response.answers().is_empty(), "{name}");
+            assert_eq!(authority(&response), [RecordType::SOA], "{name}");
+            assert_eq!(response.name_servers()[0].name().to_ascii(), "dev.");
+        }
+    }
+
+    #[tokio::test]
+    async fn tlds_answer_their_soa_and_ns() {
+        let server = nameserver("tld").await;
+
+        let soa = ask(&server, "dev.", RecordType::SOA).await;
+        assert_eq!(soa.answers().iter().map(Record::record_type).collect::<Vec<_>>(), [RecordType::SOA]);
+
+        let ns = ask(&server, "dev.", RecordType::NS).await;
+        assert_eq!(ns.answers()[0].data(), Some(&RData::NS(NS(Name::from_ascii("localhost.").unwrap()))));
+    }
+}
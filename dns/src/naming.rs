@@ -0,0 +1,221 @@
+use crate::config::{Charset, Naming};
+use unicode_security::{skeleton, GeneralSecurityProfile, MixedScript};
+
+/// A naming rule a domain name breaks
+#[derive(Debug, PartialEq)]
+pub enum Violation {
+    TooShort(usize),
+    TooLong(usize),
+    InvalidCharacter(char),
+    Digits,
+    Unicode,
+    LeadingHyphen,
+    TrailingHyphen,
+    DoubleHyphen,
+    MixedScript,
+    Confusable(String),
+    InvalidPunycode,
+}
+
+impl Violation {
+    /// Stable identifier of the rule for clients to match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            Violation::TooShort(_) => "name_too_short",
+            Violation::TooLong(_) => "name_too_long",
+            Violation::InvalidCharacter(_) => "invalid_character",
+            Violation::Digits => "digits_not_allowed",
+            Violation::Unicode => "unicode_not_allowed",
+            Violation::LeadingHyphen => "leading_hyphen",
+            Violation::TrailingHyphen => "trailing_hyphen",
+            Violation::DoubleHyphen => "double_hyphen",
+            Violation::MixedScript => "mixed_script",
+            Violation::Confusable(_) => "confusable",
+            Violation::InvalidPunycode => "invalid_punycode",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            Violation::TooShort(min) => format!("Names must be at least {min} characters long."),
+            Violation::TooLong(max) => format!("Names can be at most {max} characters long."),
+            Violation::InvalidCharacter(c) => format!("Names can not contain '{c}'."),
+            Violation::Digits => "Names can not contain digits.".into(),
+            Violation::Unicode => "Names can only contain the letters a-z.".into(),
+            Violation::LeadingHyphen => "Names can not start with a hyphen.".into(),
+            Violation::TrailingHyphen => "Names can not end with a hyphen.".into(),
+            Violation::DoubleHyphen => "Names can not contain '--' in the third and fourth position.".into(),
+            Violation::MixedScript => "Names can not mix letters of different scripts.".into(),
+            Violation::Confusable(lookalike) => format!("Name is easily confused with '{lookalike}'."),
+            Violation::InvalidPunycode => "Name is not valid punycode.".into(),
+        }
+    }
+}
+
+/// Label length limit of the DNS wire format, applies to the punycode form
+const MAX_LABEL: usize = 63;
+
+/// Normalise `name` to the form stored and served over DNS,
+/// punycode for unicode names, without checking the naming rules
+pub fn to_ascii(name: &str) -> String {
+    match name.is_ascii() {
+        true => name.to_lowercase(),
+        false => idna::domain_to_ascii(name).unwrap_or_else(|_| name.to_lowercase()),
+    }
+}
+
+/// Display form of a stored name, decoding punycode
+pub fn to_unicode(name: &str) -> String { idna::domain_to_unicode(name).0 }
+
+/// Alternatives to a taken `name`, best first: with a common prefix or suffix,
+/// then one edit away. They still have to be checked against the naming rules
+pub fn variants(name: &str) -> Vec<String> {
+    const PREFIXES: [&str; 3] = ["get", "my", "the"];
+    const SUFFIXES: [&str; 5] = ["app", "hq", "web", "site", "online"];
+
+    let chars: Vec<char> = name.chars().collect();
+    let join = |chars: &[char]| chars.iter().collect::<String>();
+    let mut variants = Vec::new();
+
+    for suffix in SUFFIXES {
+        variants.extend([format!("{name}-{suffix}"), format!("{name}{suffix}")]);
+    }
+
+    for prefix in PREFIXES {
+        variants.extend([format!("{prefix}-{name}"), format!("{prefix}{name}")]);
+    }
+
+    variants.extend((1..=3).map(|digit| format!("{name}{digit}")));
+
+    // a hyphen inserted, a character dropped or two neighbours swapped
+    variants.extend((1..chars.len()).map(|at| format!("{}-{}", join(&chars[..at]), join(&chars[at..]))));
+    variants.extend((0..chars.len()).map(|at| format!("{}{}", join(&chars[..at]), join(&chars[at + 1..]))));
+    variants.extend((1..chars.len()).filter(|at| chars[at - 1] != chars[*at]).map(|at| {
+        let mut swapped = chars.clone();
+        swapped.swap(at - 1, at);
+        join(&swapped)
+    }));
+
+    let mut seen = std::collections::HashSet::new();
+    variants.retain(|variant| variant != name && seen.insert(variant.clone()));
+    variants
+}
+
+/// Check `name` against `policy`, returning the normalised name to store
+pub fn validate(name: &str, policy: &Naming) -> Result<String, Violation> {
+    let name = name.to_lowercase();
+
+    // checked before normalising as punycode conversion rejects or maps these
+    if let Some(c) = name.chars().find(|c| c.is_ascii() && !matches!(c, 'a'..='z' | '0'..='9' | '-')) {
+        return Err(Violation::InvalidCharacter(c));
+    }
+
+    let (unicode, ascii) = match name.is_ascii() && !name.starts_with("xn--") {
+        true => (name.clone(), name),
+        false if policy.charset == Charset::Ascii => return Err(Violation::Unicode),
+        false => {
+            let ascii = idna::domain_to_ascii(&name).map_err(|_| Violation::InvalidPunycode)?;
+            let (unicode, result) = idna::domain_to_unicode(&ascii);
+
+            result.map_err(|_| Violation::InvalidPunycode)?;
+            (unicode, ascii)
+        }
+    };
+
+    let length = unicode.chars().count();
+
+    if length < policy.min_length {
+        return Err(Violation::TooShort(policy.min_length));
+    }
+
+    if length > policy.max_length || ascii.len() > MAX_LABEL {
+        return Err(Violation::TooLong(policy.max_length.min(MAX_LABEL)));
+    }
+
+    for c in unicode.chars() {
+        match c {
+            '-' | 'a'..='z' => {}
+            '0'..='9' if policy.digits => {}
+            '0'..='9' => return Err(Violation::Digits),
+            c if !c.is_ascii() && c.identifier_allowed() && policy.charset == Charset::Unicode => {}
+            c => return Err(Violation::InvalidCharacter(c)),
+        }
+    }
+
+    if !policy.leading_hyphen && unicode.starts_with('-') {
+        return Err(Violation::LeadingHyphen);
+    }
+
+    if !policy.trailing_hyphen && unicode.ends_with('-') {
+        return Err(Violation::TrailingHyphen);
+    }
+
+    if !policy.double_hyphen && unicode.get(2..4) == Some("--") {
+        return Err(Violation::DoubleHyphen);
+    }
+
+    if policy.confusables && !unicode.is_ascii() {
+        if !unicode.as_str().is_single_script() {
+            return Err(Violation::MixedScript);
+        }
+
+        // a name made only of lookalikes of latin letters, e.g. a cyrillic "аррӏе"
+        let lookalike: String = skeleton(&unicode).collect();
+        if lookalike.is_ascii() {
+            return Err(Violation::Confusable(lookalike));
+        }
+    }
+
+    Ok(ascii)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Tld;
+
+    fn policy() -> Naming { Naming::default() }
+
+    #[test]
+    fn ascii_names_are_lowercased() {
+        assert_eq!(validate("Example", &policy()), Ok("example".into()));
+        assert_eq!(validate("a_b", &policy()), Err(Violation::InvalidCharacter('_')));
+        assert_eq!(validate("abc1", &policy()), Err(Violation::Digits));
+        assert_eq!(validate("-abc", &policy()), Err(Violation::LeadingHyphen));
+        assert_eq!(validate("ab--cd", &policy()), Err(Violation::DoubleHyphen));
+        assert_eq!(validate(&"a".repeat(25), &policy()), Err(Violation::TooLong(24)));
+    }
+
+    #[test]
+    fn unicode_names_are_stored_as_punycode() {
+        assert_eq!(validate("münchen", &policy()), Ok("xn--mnchen-3ya".into()));
+        assert_eq!(validate("xn--mnchen-3ya", &policy()), Ok("xn--mnchen-3ya".into()));
+        assert_eq!(validate("xn--a", &policy()), Err(Violation::InvalidPunycode));
+
+        let ascii = Naming { charset: Charset::Ascii, ..policy() };
+        assert_eq!(validate("münchen", &ascii), Err(Violation::Unicode));
+    }
+
+    #[test]
+    fn lookalikes_are_refused() {
+        // cyrillic letters only
+        assert_eq!(validate("сосоа", &policy()), Err(Violation::Confusable("cocoa".into())));
+        // a cyrillic "а" among latin letters
+        assert_eq!(validate("pаypal", &policy()), Err(Violation::MixedScript));
+
+        let lenient = Naming { confusables: false, ..policy() };
+        assert!(validate("сосоа", &lenient).is_ok());
+    }
+
+    #[test]
+    fn reserved_names_match_their_stored_form() {
+        let tld = Tld {
+            reserved: vec!["admin".into(), "München".into()],
+            ..Tld::default()
+        };
+
+        assert!(tld.reserves("admin"));
+        assert!(tld.reserves("xn--mnchen-3ya"));
+        assert!(!tld.reserves("other"));
+    }
+}
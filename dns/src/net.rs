@@ -0,0 +1,67 @@
+use anyhow::{anyhow, Error};
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+};
+use tokio::net::lookup_host;
+
+/// A host refused because it resolves to an address that is not public
+#[derive(Debug)]
+pub(crate) struct NotPublic(pub(crate) String);
+
+impl fmt::Display for NotPublic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{} does not resolve to a public address", self.0) }
+}
+
+impl std::error::Error for NotPublic {}
+
+/// Whether `ip` is reachable from the internet, loopback, private, link-local and metadata addresses are not
+pub(crate) fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            let shared = first == 100 && (64..128).contains(&second);
+            !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast() || ip.is_documentation() || first == 0 || shared)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || ip.is_unique_local() || ip.is_unicast_link_local()),
+        },
+    }
+}
+
+/// Resolve `host` to the address to connect to, failing with `NotPublic` on non-public ones unless `allow_private`
+pub(crate) async fn resolve(host: &str, port: u16, allow_private: bool) -> Result<SocketAddr, Error> {
+    // every address is checked, a name could mix public and private ones
+    let addrs: Vec<SocketAddr> = lookup_host((host.trim_start_matches('[').trim_end_matches(']'), port))
+        .await
+        .map_err(|_| anyhow!("Could not resolve {host}"))?
+        .collect();
+
+    match addrs.first() {
+        None => Err(anyhow!("Could not resolve {host}")),
+        Some(_) if !allow_private && addrs.iter().any(|addr| !is_public(addr.ip())) => Err(NotPublic(host.to_string()).into()),
+        Some(addr) => Ok(*addr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn public_addresses() {
+        for ip in ["1.1.1.1", "2606:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["100.64.0.1", "192.168.1.1", "172.16.0.1", "169.254.169.254", "0.0.0.0", "::ffff:127.0.0.1", "fe80::1", "fd00::1"] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[tokio::test]
+    async fn private_hosts_are_refused() {
+        assert!(resolve("localhost", 80, false).await.unwrap_err().is::<NotPublic>());
+        assert!(resolve("127.0.0.1", 80, true).await.is_ok());
+    }
+}
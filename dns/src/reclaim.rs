@@ -0,0 +1,168 @@
+use crate::{
+    cache::Cache,
+    config::{self, Config, SharedConfig},
+    events::{EventKind, Events},
+    http::{self, Cursor, Domain, Order, SortBy},
+    net,
+    store::{DomainStore, Search},
+    time,
+};
+use anyhow::Error;
+use futures::stream::{self, StreamExt};
+use std::{sync::Arc, time::Duration};
+use tokio::net::TcpStream;
+
+/// Health checks running at once
+const CONCURRENCY: usize = 16;
+const PAGE_SIZE: u64 = 100;
+
+/// Release domains past their grace period, health check the others and prune old events, every `reclaim.interval`
+pub(crate) fn spawn(config: SharedConfig, db: Arc<dyn DomainStore>, events: Arc<Events>, cache: Arc<Cache>) {
+    tokio::spawn(async move {
+        loop {
+            let current = config.load_full();
+
+            match release_expired(&current, db.as_ref(), &events, &cache).await {
+                Ok(0) => {}
+                Ok(released) => log::info!("Released {released} expired domains"),
+                Err(err) => log::error!("Failed to release expired domains: {err}"),
+            }
+
+            if current.settings.reclaim.health_check {
+                if let Err(err) = check_health(&current, db.as_ref(), &cache).await {
+                    log::error!("Failed to health check domains: {err}");
+                }
+            }
+
+            let retention = current.settings.event_retention * config::DAY;
+            match db.prune_events(time::now().saturating_sub(retention)).await {
+                Ok(0) => {}
+                Ok(pruned) => log::debug!("Pruned {pruned} events older than {} days", current.settings.event_retention),
+                Err(err) => log::error!("Failed to prune events: {err}"),
+            }
+
+            tokio::time::sleep(Duration::from_secs(current.settings.reclaim.interval)).await;
+        }
+    });
+}
+
+async fn release_expired(config: &Config, db: &dyn DomainStore, events: &Events, cache: &Cache) -> Result<usize, Error> {
+    let now = time::now();
+    let mut released = 0;
+
+    for domain in db.expired(now).await? {
+        // domains on tlds that no longer expire could not have been renewed
+        let Some(expires) = domain.expiry(config) else { continue };
+
+        // suspended names stay taken until a moderator lifts or deletes the suspension
+        if domain.suspended.is_some() || expires.saturating_add(config.grace_for(&domain.tld)) > now {
+            continue;
+        }
+
+        // a renewal since the domain was read keeps it
+        if db.delete_expired(domain.secret_key.as_deref().unwrap_or_default(), expires).await? {
+            log::info!("Released {}.{}, expired at {}", domain.name, domain.tld, time::format(Some(expires)));
+            cache.forget(&domain.name, &domain.tld);
+            events.emit(EventKind::Deleted, &domain).await;
+            released += 1;
+        }
+    }
+
+    Ok(released)
+}
+
+/// Host and port a target accepts connections on, web servers for addresses
+fn endpoint(target: &str) -> Option<(String, u16)> {
+    if let Ok(ip) = target.parse::<std::net::IpAddr>() {
+        return Some((ip.to_string(), 80));
+    }
+
+    let (rest, port) = match target.split_once("://") {
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("https") => (rest, 443),
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => (rest, 80),
+        _ => return None,
+    };
+
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+
+    let (host, custom) = match host.strip_prefix('[') {
+        Some(bracketed) => bracketed.split_once(']').map(|(ip, rest)| (ip, rest.strip_prefix(':')))?,
+        None => host.split_once(':').map_or((host, None), |(name, port)| (name, Some(port))),
+    };
+
+    match custom {
+        Some(custom) => Some((host.to_string(), custom.parse().ok()?)),
+        None => Some((host.to_string(), port)),
+    }
+}
+
+/// Whether `target` accepts connections, `None` when it is not checked because it is not public
+async fn reachable(target: &str, timeout: Duration, allow_private: bool) -> Option<bool> {
+    let Some((host, port)) = endpoint(target) else { return Some(false) };
+
+    // the outcome is public, checking internal addresses would let anyone scan them
+    let addr = match tokio::time::timeout(timeout, net::resolve(&host, port, allow_private)).await {
+        Ok(Ok(addr)) => addr,
+        Ok(Err(err)) if err.is::<net::NotPublic>() => return None,
+        _ => return Some(false),
+    };
+
+    Some(matches!(tokio::time::timeout(timeout, TcpStream::connect(addr)).await, Ok(Ok(_))))
+}
+
+/// Flag domains whose target stopped accepting connections, and clear the flag once it recovers
+async fn check_health(config: &Config, db: &dyn DomainStore, cache: &Cache) -> Result<(), Error> {
+    let timeout = Duration::from_secs(config.settings.reclaim.health_timeout);
+    let allow_private = config.settings.verification.allow_private;
+    let mut after: Option<Cursor> = None;
+
+    loop {
+        let search = Search {
+            tld: None,
+            prefix: None,
+            contains: None,
+            sort: SortBy::Name,
+            order: Order::Asc,
+            after: after.take(),
+            skip: 0,
+            limit: PAGE_SIZE,
+        };
+
+        let (page, _) = db.search(&search).await?;
+        after = page.last().map(Cursor::of);
+
+        let targets = page.into_iter().filter(|domain| !domain.is_expired(time::now(), config)).filter_map(|domain| {
+            let target = http::target(&domain.records())?.value.clone();
+            Some((domain, target))
+        });
+
+        let checked: Vec<(Domain, Option<bool>)> = stream::iter(targets)
+            .map(|(domain, target)| async move { (domain, reachable(&target, timeout, allow_private).await) })
+            .buffer_unordered(CONCURRENCY)
+            .collect()
+            .await;
+
+        for (domain, alive) in checked.into_iter().filter_map(|(domain, alive)| Some((domain, alive?))) {
+            let key = domain.secret_key.as_deref().unwrap_or_default();
+
+            match (alive, domain.unreachable) {
+                (false, None) => {
+                    log::warn!("{}.{} is unreachable", domain.name, domain.tld);
+                    db.set_unreachable(key, Some(time::now())).await?;
+                    cache.forget(&domain.name, &domain.tld);
+                }
+                (true, Some(_)) => {
+                    log::info!("{}.{} is reachable again", domain.name, domain.tld);
+                    db.set_unreachable(key, None).await?;
+                    cache.forget(&domain.name, &domain.tld);
+                }
+                _ => {}
+            }
+        }
+
+        if after.is_none() {
+            return Ok(());
+        }
+    }
+}
@@ -1,4 +1,10 @@
 use rand::{rngs::StdRng, Rng, SeedableRng};
+use sha2::{Digest, Sha256};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
 
 pub fn generate(size: usize) -> String {
     const ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
@@ -24,3 +30,24 @@ pub fn generate(size: usize) -> String {
 
     id
 }
+
+/// Salted argon2 hash, for secrets that are looked up by name
+pub fn hash(value: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default().hash_password(value.as_bytes(), &salt)?.to_string())
+}
+
+/// Check a secret against its argon2 hash in constant time
+pub fn verify(value: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default().verify_password(value.as_bytes(), &parsed).is_ok(),
+        Err(_) => false,
+    }
+}
+
+pub fn is_hash(value: &str) -> bool { value.starts_with("$argon2") }
+
+/// SHA-256 digest, for random secrets that are looked up by value
+pub fn digest(value: &str) -> String { format!("{:x}", Sha256::digest(value.as_bytes())) }
+
+pub fn is_digest(value: &str) -> bool { value.len() == 64 && value.bytes().all(|byte| byte.is_ascii_hexdigit()) }
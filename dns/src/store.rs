@@ -0,0 +1,159 @@
+mod embedded;
+mod mongo;
+
+use crate::events::Event;
+use crate::http::{Change, Cursor, Domain, Order, Owner, Record, Reservation, SortBy, Suspension, Verification};
+use anyhow::Error;
+use async_trait::async_trait;
+use std::{cmp::Ordering, fmt};
+
+pub(crate) use embedded::EmbeddedStore;
+pub(crate) use mongo::MongoStore;
+
+/// Filters, ordering and position of a domain listing
+pub(crate) struct Search {
+    pub(crate) tld: Option<String>,
+    /// Start of the ASCII name
+    pub(crate) prefix: Option<String>,
+    /// Part of the ASCII name
+    pub(crate) contains: Option<String>,
+    pub(crate) sort: SortBy,
+    pub(crate) order: Order,
+    /// Only list domains ordered after this one
+    pub(crate) after: Option<Cursor>,
+    pub(crate) skip: u64,
+    pub(crate) limit: u64,
+}
+
+impl Search {
+    pub(crate) fn matches(&self, domain: &Domain) -> bool {
+        self.tld.as_ref().is_none_or(|tld| &domain.tld == tld)
+            && self.prefix.as_ref().is_none_or(|prefix| domain.name.starts_with(prefix.as_str()))
+            && self.contains.as_ref().is_none_or(|part| domain.name.contains(part.as_str()))
+    }
+
+    fn directed(&self, ordering: Ordering) -> Ordering {
+        match self.order {
+            Order::Asc => ordering,
+            Order::Desc => ordering.reverse(),
+        }
+    }
+
+    pub(crate) fn compare(&self, a: &Domain, b: &Domain) -> Ordering { self.directed(Cursor::of(a).key(self.sort).cmp(&Cursor::of(b).key(self.sort))) }
+
+    pub(crate) fn is_after(&self, domain: &Domain) -> bool {
+        match &self.after {
+            Some(cursor) => self.directed(Cursor::of(domain).key(self.sort).cmp(&cursor.key(self.sort))).is_gt(),
+            None => true,
+        }
+    }
+}
+
+/// A write refused because the domain or owner name is already taken, other errors are failures of the backend
+#[derive(Debug)]
+pub(crate) struct Conflict(pub(crate) String);
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{} already exists", self.0) }
+}
+
+impl std::error::Error for Conflict {}
+
+#[async_trait]
+pub(crate) trait DomainStore: Send + Sync {
+    /// Find a domain by its name and tld
+    async fn find(&self, name: &str, tld: &str) -> Result<Option<Domain>, Error>;
+
+    /// Find the registered domains among `names`, pairs of name and tld, in one query
+    async fn find_many(&self, names: &[(String, String)]) -> Result<Vec<Domain>, Error>;
+
+    /// Find the domain owning `key`
+    async fn find_by_key(&self, key: &str) -> Result<Option<Domain>, Error>;
+
+    /// Insert a new domain, failing with `Conflict` if the name is already registered
+    async fn insert(&self, domain: &Domain) -> Result<(), Error>;
+
+    /// Set the ip and records of the domain owning `key`, returns false if none matched.
+    /// A changed ip is appended to the history of the domain as made `by`
+    async fn update(&self, key: &str, ip: &str, records: &[Record], by: &str) -> Result<bool, Error>;
+
+    /// Every recorded ip change of `name` on `tld`, oldest first
+    async fn history(&self, name: &str, tld: &str) -> Result<Vec<Change>, Error>;
+
+    /// Replace the domain registered under the name and tld of `domain` at once, keys included,
+    /// returns false if none is registered. Fails with `Conflict` if the new key is taken
+    async fn replace(&self, domain: &Domain) -> Result<bool, Error>;
+
+    /// Delete the domain owning `key` if it is not suspended and still expires at `expires`, returns false otherwise
+    async fn delete_expired(&self, key: &str, expires: u64) -> Result<bool, Error>;
+
+    /// Delete the domain owning `key`, returns false if none matched
+    async fn delete(&self, key: &str) -> Result<bool, Error>;
+
+    /// List the domains matching `search`, along with the number of matches before paging
+    async fn search(&self, search: &Search) -> Result<(Vec<Domain>, u64), Error>;
+
+    /// Replace the secret key of the domain owning `key`
+    async fn rotate(&self, key: &str, new_key: &str) -> Result<bool, Error>;
+
+    /// Assign the domain owning `key` to the account `owner`
+    async fn set_owner(&self, key: &str, owner: &str) -> Result<bool, Error>;
+
+    /// Assign the domain owning `key` to `owner` and replace its key at once, so the previous holder loses control
+    async fn transfer(&self, key: &str, owner: &str, new_key: &str) -> Result<bool, Error>;
+
+    /// Set when the registration of the domain owning `key` ends
+    async fn renew(&self, key: &str, expires: u64) -> Result<bool, Error>;
+
+    /// Domains whose registration ended before `time`
+    async fn expired(&self, time: u64) -> Result<Vec<Domain>, Error>;
+
+    /// Flag the target of the domain owning `key` as failing health checks since a time, or clear it with `None`
+    async fn set_unreachable(&self, key: &str, since: Option<u64>) -> Result<bool, Error>;
+
+    /// Suspend the domain owning `key`, or lift its suspension with `None`
+    async fn set_suspension(&self, key: &str, suspension: Option<&Suspension>) -> Result<bool, Error>;
+
+    /// Store the verification of the target of the domain owning `key`
+    async fn set_verification(&self, key: &str, verification: &Verification) -> Result<bool, Error>;
+
+    /// List every domain held by the account `owner`
+    async fn find_by_owner(&self, owner: &str) -> Result<Vec<Domain>, Error>;
+
+    /// Find an owner account by name
+    async fn find_owner(&self, name: &str) -> Result<Option<Owner>, Error>;
+
+    /// Insert a new owner account, failing with `Conflict` if the name is taken
+    async fn insert_owner(&self, owner: &Owner) -> Result<(), Error>;
+
+    /// Find the reservation covering `name` on `tld`, either for that tld or for every tld
+    async fn find_reservation(&self, name: &str, tld: &str) -> Result<Option<Reservation>, Error>;
+
+    /// Every reservation of the names in `names`, for any tld
+    async fn find_reservations(&self, names: &[String]) -> Result<Vec<Reservation>, Error>;
+
+    /// Reserve a name, replacing any existing reservation for the same name and tld
+    async fn reserve(&self, reservation: &Reservation) -> Result<(), Error>;
+
+    /// Lift the reservation of `name` on `tld`, or the one covering every tld with `None`
+    async fn release(&self, name: &str, tld: Option<&str>) -> Result<bool, Error>;
+
+    /// List every reservation
+    async fn reservations(&self) -> Result<Vec<Reservation>, Error>;
+
+    /// Append to the event log, returning the event with its assigned id
+    async fn append_event(&self, event: Event) -> Result<Event, Error>;
+
+    /// Up to `limit` events following the event `id`, oldest first
+    async fn events_after(&self, id: u64, limit: u64) -> Result<Vec<Event>, Error>;
+
+    /// Drop events that happened before `time`, returns how many were dropped
+    async fn prune_events(&self, time: u64) -> Result<u64, Error>;
+
+    /// Replace plaintext domain keys and owner tokens with their hashes,
+    /// returns the number of migrated entries
+    async fn migrate_secrets(&self) -> Result<u64, Error>;
+
+    /// Fail when the backend can not be reached
+    async fn ping(&self) -> Result<(), Error>;
+}
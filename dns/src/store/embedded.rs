@@ -0,0 +1,592 @@
+use super::{Conflict, DomainStore, Search};
+use crate::{
+    events::Event,
+    http::{Change, Domain, Owner, Record, Reservation, Suspension, Verification},
+    secret, time,
+};
+use anyhow::{anyhow, Error};
+use async_trait::async_trait;
+use sled::{
+    transaction::{ConflictableTransactionError, ConflictableTransactionResult, TransactionError, TransactionalTree},
+    Transactional, Tree,
+};
+
+pub(crate) struct EmbeddedStore {
+    db: sled::Db,
+    domains: Tree,
+    secrets: Tree,
+    owners: Tree,
+    reserved: Tree,
+    history: Tree,
+    events: Tree,
+}
+
+/// Key of the event id counter in the default tree
+const EVENT_SEQ: &str = "event_seq";
+
+fn counter(value: Option<&[u8]>) -> u64 { value.and_then(|value| value.try_into().ok()).map_or(0, u64::from_be_bytes) }
+
+fn domain_key(name: &str, tld: &str) -> String { format!("{name}.{tld}") }
+
+/// History entries of a domain are keyed by this prefix and an increasing id
+fn history_prefix(name: &str, tld: &str) -> String { format!("{}/", domain_key(name, tld)) }
+
+/// Reservations covering every tld are stored under `name.*`
+fn reservation_key(name: &str, tld: Option<&str>) -> String { domain_key(name, tld.unwrap_or("*")) }
+
+/// The domain owning `key` and its id, read inside a transaction
+fn keyed(domains: &TransactionalTree, secrets: &TransactionalTree, key: &str) -> ConflictableTransactionResult<Option<(sled::IVec, Domain)>, Error> {
+    let Some(id) = secrets.get(key)? else { return Ok(None) };
+    let Some(value) = domains.get(&id)? else { return Ok(None) };
+
+    match serde_json::from_slice(&value) {
+        Ok(domain) => Ok(Some((id, domain))),
+        Err(err) => Err(ConflictableTransactionError::Abort(err.into())),
+    }
+}
+
+fn encode(domain: &Domain) -> ConflictableTransactionResult<Vec<u8>, Error> { serde_json::to_vec(domain).map_err(|err| ConflictableTransactionError::Abort(err.into())) }
+
+/// Aborts and storage failures of a transaction as one error
+fn failed(err: TransactionError<Error>) -> Error {
+    match err {
+        TransactionError::Abort(err) => err,
+        TransactionError::Storage(err) => err.into(),
+    }
+}
+
+impl EmbeddedStore {
+    pub fn open(path: &String) -> Result<Self, Error> {
+        let db = sled::open(path)?;
+
+        log::info!("Embedded database opened at {path}");
+        Ok(Self {
+            domains: db.open_tree("domains")?,
+            secrets: db.open_tree("secrets")?,
+            owners: db.open_tree("owners")?,
+            reserved: db.open_tree("reserved")?,
+            history: db.open_tree("history")?,
+            events: db.open_tree("events")?,
+            db,
+        })
+    }
+
+    fn by_key(&self, key: &str) -> Result<Option<(sled::IVec, Domain)>, Error> {
+        let Some(id) = self.secrets.get(key)? else { return Ok(None) };
+
+        match self.domains.get(&id)? {
+            Some(value) => Ok(Some((id, serde_json::from_slice(&value)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Change the domain owning `key` in one transaction, so concurrent changes are not lost.
+    /// Returns the domain as it was before, `apply` may run again when the transaction is retried
+    fn modify(&self, key: &str, apply: impl Fn(&mut Domain)) -> Result<Option<Domain>, Error> {
+        (&self.domains, &self.secrets)
+            .transaction(|(domains, secrets)| {
+                let Some((id, domain)) = keyed(domains, secrets, key)? else { return Ok(None) };
+                let mut changed = domain.clone();
+
+                apply(&mut changed);
+                domains.insert(&id, encode(&changed)?)?;
+
+                Ok(Some(domain))
+            })
+            .map_err(failed)
+    }
+
+    /// Replace the key of the domain owning `key`, assigning it to `owner` when given
+    async fn rekey(&self, key: &str, new_key: &str, owner: Option<&str>) -> Result<bool, Error> {
+        // the old key is looked up inside the transaction, so of two rotations racing only one succeeds
+        let rotated = (&self.domains, &self.secrets)
+            .transaction(|(domains, secrets)| {
+                let Some((id, mut domain)) = keyed(domains, secrets, key)? else { return Ok(false) };
+                domain.secret_key = Some(new_key.to_string());
+                if let Some(owner) = owner {
+                    domain.owner = Some(owner.to_string());
+                }
+
+                secrets.remove(key.as_bytes())?;
+                secrets.insert(new_key.as_bytes(), &id)?;
+                domains.insert(&id, encode(&domain)?)?;
+
+                Ok(true)
+            })
+            .map_err(failed)?;
+
+        self.domains.flush_async().await?;
+        Ok(rotated)
+    }
+}
+
+#[async_trait]
+impl DomainStore for EmbeddedStore {
+    async fn find(&self, name: &str, tld: &str) -> Result<Option<Domain>, Error> {
+        match self.domains.get(domain_key(name, tld))? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn find_many(&self, names: &[(String, String)]) -> Result<Vec<Domain>, Error> {
+        let mut domains = Vec::new();
+
+        for (name, tld) in names {
+            if let Some(value) = self.domains.get(domain_key(name, tld))? {
+                domains.push(serde_json::from_slice(&value)?);
+            }
+        }
+
+        Ok(domains)
+    }
+
+    async fn find_by_key(&self, key: &str) -> Result<Option<Domain>, Error> { Ok(self.by_key(key)?.map(|(_, domain)| domain)) }
+
+    async fn insert(&self, domain: &Domain) -> Result<(), Error> {
+        let id = domain_key(&domain.name, &domain.tld);
+        let value = serde_json::to_vec(domain)?;
+
+        (&self.domains, &self.secrets)
+            .transaction(|(domains, secrets)| {
+                if domains.get(&id)?.is_some() {
+                    return Err(ConflictableTransactionError::Abort(Conflict(format!("Domain {id}")).into()));
+                }
+
+                domains.insert(id.as_bytes(), value.as_slice())?;
+                if let Some(key) = &domain.secret_key {
+                    secrets.insert(key.as_bytes(), id.as_bytes())?;
+                }
+
+                Ok(())
+            })
+            .map_err(failed)?;
+
+        self.domains.flush_async().await?;
+        Ok(())
+    }
+
+    async fn update(&self, key: &str, ip: &str, records: &[Record], by: &str) -> Result<bool, Error> {
+        let now = time::now();
+        let Some(domain) = self.modify(key, |domain| {
+            domain.ip = ip.to_string();
+            domain.records = records.to_vec();
+            domain.updated = now;
+        })?
+        else {
+            return Ok(false);
+        };
+
+        if domain.ip != ip {
+            let change = Change {
+                at: now,
+                by: by.to_string(),
+                new: ip.to_string(),
+                name: domain.name,
+                tld: domain.tld,
+                old: domain.ip,
+            };
+
+            // ids only grow, so entries of a domain are listed in the order they were made
+            let mut entry = history_prefix(&change.name, &change.tld).into_bytes();
+            entry.extend_from_slice(&self.db.generate_id()?.to_be_bytes());
+            self.history.insert(entry, serde_json::to_vec(&change)?)?;
+            self.history.flush_async().await?;
+        }
+
+        self.domains.flush_async().await?;
+        Ok(true)
+    }
+
+    async fn history(&self, name: &str, tld: &str) -> Result<Vec<Change>, Error> {
+        Ok(self
+            .history
+            .scan_prefix(history_prefix(name, tld))
+            .values()
+            .filter_map(|value| value.ok().and_then(|value| serde_json::from_slice(&value).ok()))
+            .collect())
+    }
+
+    async fn replace(&self, domain: &Domain) -> Result<bool, Error> {
+        let id = domain_key(&domain.name, &domain.tld);
+        let value = serde_json::to_vec(domain)?;
+
+        let replaced = (&self.domains, &self.secrets)
+            .transaction(|(domains, secrets)| {
+                let Some(previous) = domains.get(&id)? else { return Ok(false) };
+                let previous: Domain = serde_json::from_slice(&previous).map_err(|err| ConflictableTransactionError::Abort(err.into()))?;
+
+                if let Some(key) = &previous.secret_key {
+                    secrets.remove(key.as_bytes())?;
+                }
+                if let Some(key) = &domain.secret_key {
+                    if secrets.insert(key.as_bytes(), id.as_bytes())?.is_some() {
+                        return Err(ConflictableTransactionError::Abort(Conflict(format!("Key of {id}")).into()));
+                    }
+                }
+
+                domains.insert(id.as_bytes(), value.as_slice())?;
+                Ok(true)
+            })
+            .map_err(failed)?;
+
+        self.domains.flush_async().await?;
+        Ok(replaced)
+    }
+
+    async fn delete_expired(&self, key: &str, expires: u64) -> Result<bool, Error> {
+        let deleted = (&self.domains, &self.secrets)
+            .transaction(|(domains, secrets)| {
+                let Some((id, domain)) = keyed(domains, secrets, key)? else { return Ok(false) };
+                if domain.suspended.is_some() || domain.expires != Some(expires) {
+                    return Ok(false);
+                }
+
+                secrets.remove(key.as_bytes())?;
+                domains.remove(&id)?;
+                Ok(true)
+            })
+            .map_err(failed)?;
+
+        self.domains.flush_async().await?;
+        Ok(deleted)
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, Error> {
+        let deleted = (&self.domains, &self.secrets)
+            .transaction(|(domains, secrets)| {
+                let Some(id) = secrets.remove(key.as_bytes())? else { return Ok(false) };
+
+                domains.remove(&id)?;
+                Ok::<_, ConflictableTransactionError<Error>>(true)
+            })
+            .map_err(failed)?;
+
+        self.domains.flush_async().await?;
+        Ok(deleted)
+    }
+
+    async fn search(&self, search: &Search) -> Result<(Vec<Domain>, u64), Error> {
+        let mut domains: Vec<Domain> = self
+            .domains
+            .iter()
+            .values()
+            .filter_map(|value| value.ok().and_then(|value| serde_json::from_slice(&value).ok()))
+            .filter(|domain| search.matches(domain))
+            .collect();
+
+        let total = domains.len() as u64;
+        domains.sort_by(|a, b| search.compare(a, b));
+
+        Ok((
+            domains.into_iter().filter(|domain| search.is_after(domain)).skip(search.skip as usize).take(search.limit as usize).collect(),
+            total,
+        ))
+    }
+
+    async fn rotate(&self, key: &str, new_key: &str) -> Result<bool, Error> { self.rekey(key, new_key, None).await }
+
+    async fn transfer(&self, key: &str, owner: &str, new_key: &str) -> Result<bool, Error> { self.rekey(key, new_key, Some(owner)).await }
+
+    async fn set_owner(&self, key: &str, owner: &str) -> Result<bool, Error> {
+        let updated = self.modify(key, |domain| domain.owner = Some(owner.to_string()))?.is_some();
+
+        self.domains.flush_async().await?;
+        Ok(updated)
+    }
+
+    async fn renew(&self, key: &str, expires: u64) -> Result<bool, Error> {
+        let updated = self.modify(key, |domain| domain.expires = Some(expires))?.is_some();
+
+        self.domains.flush_async().await?;
+        Ok(updated)
+    }
+
+    async fn expired(&self, time: u64) -> Result<Vec<Domain>, Error> {
+        Ok(self
+            .domains
+            .iter()
+            .values()
+            .filter_map(|value| value.ok().and_then(|value| serde_json::from_slice::<Domain>(&value).ok()))
+            .filter(|domain| domain.expires.is_some_and(|expires| expires < time))
+            .collect())
+    }
+
+    async fn set_unreachable(&self, key: &str, since: Option<u64>) -> Result<bool, Error> {
+        let updated = self.modify(key, |domain| domain.unreachable = since)?.is_some();
+
+        self.domains.flush_async().await?;
+        Ok(updated)
+    }
+
+    async fn set_suspension(&self, key: &str, suspension: Option<&Suspension>) -> Result<bool, Error> {
+        let updated = self.modify(key, |domain| domain.suspended = suspension.cloned())?.is_some();
+
+        self.domains.flush_async().await?;
+        Ok(updated)
+    }
+
+    async fn set_verification(&self, key: &str, verification: &Verification) -> Result<bool, Error> {
+        let updated = self.modify(key, |domain| domain.verification = Some(verification.clone()))?.is_some();
+
+        self.domains.flush_async().await?;
+        Ok(updated)
+    }
+
+    async fn find_by_owner(&self, owner: &str) -> Result<Vec<Domain>, Error> {
+        Ok(self
+            .domains
+            .iter()
+            .values()
+            .filter_map(|value| value.ok().and_then(|value| serde_json::from_slice::<Domain>(&value).ok()))
+            .filter(|domain| domain.owner.as_deref() == Some(owner))
+            .collect())
+    }
+
+    async fn find_owner(&self, name: &str) -> Result<Option<Owner>, Error> {
+        match self.owners.get(name)? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn insert_owner(&self, owner: &Owner) -> Result<(), Error> {
+        let value = serde_json::to_vec(owner)?;
+
+        match self.owners.compare_and_swap(&owner.name, None as Option<&[u8]>, Some(value))? {
+            Ok(()) => Ok(self.owners.flush_async().await.map(|_| ())?),
+            Err(_) => Err(Conflict(format!("Owner {}", owner.name)).into()),
+        }
+    }
+
+    async fn find_reservations(&self, names: &[String]) -> Result<Vec<Reservation>, Error> {
+        let mut reservations = Vec::new();
+
+        for name in names {
+            for entry in self.reserved.scan_prefix(format!("{name}.")) {
+                reservations.push(serde_json::from_slice(&entry?.1)?);
+            }
+        }
+
+        Ok(reservations)
+    }
+
+    async fn find_reservation(&self, name: &str, tld: &str) -> Result<Option<Reservation>, Error> {
+        for key in [reservation_key(name, Some(tld)), reservation_key(name, None)] {
+            if let Some(value) = self.reserved.get(key)? {
+                return Ok(Some(serde_json::from_slice(&value)?));
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn reserve(&self, reservation: &Reservation) -> Result<(), Error> {
+        self.reserved.insert(reservation_key(&reservation.name, reservation.tld.as_deref()), serde_json::to_vec(reservation)?)?;
+        self.reserved.flush_async().await?;
+        Ok(())
+    }
+
+    async fn release(&self, name: &str, tld: Option<&str>) -> Result<bool, Error> {
+        let removed = self.reserved.remove(reservation_key(name, tld))?.is_some();
+        self.reserved.flush_async().await?;
+        Ok(removed)
+    }
+
+    async fn reservations(&self) -> Result<Vec<Reservation>, Error> {
+        Ok(self.reserved.iter().values().filter_map(|value| value.ok().and_then(|value| serde_json::from_slice(&value).ok())).collect())
+    }
+
+    async fn append_event(&self, mut event: Event) -> Result<Event, Error> {
+        // a counter of its own keeps ids consecutive, they start at 1 so subscribers can ask for everything after 0
+        let seq = self.db.update_and_fetch(EVENT_SEQ, |last| Some((counter(last) + 1).to_be_bytes().to_vec()))?;
+        event.id = counter(seq.as_deref());
+        self.events.insert(event.id.to_be_bytes(), serde_json::to_vec(&event)?)?;
+        self.events.flush_async().await?;
+        Ok(event)
+    }
+
+    async fn events_after(&self, id: u64, limit: u64) -> Result<Vec<Event>, Error> {
+        let Some(next) = id.checked_add(1) else { return Ok(Vec::new()) };
+
+        Ok(self
+            .events
+            .range(next.to_be_bytes()..)
+            .values()
+            .take(limit as usize)
+            .filter_map(|value| value.ok().and_then(|value| serde_json::from_slice(&value).ok()))
+            .collect())
+    }
+
+    async fn prune_events(&self, time: u64) -> Result<u64, Error> {
+        let mut pruned = 0;
+
+        for row in self.events.iter() {
+            let (key, value) = row?;
+            match serde_json::from_slice::<Event>(&value) {
+                Ok(event) if event.at >= time => break,
+                _ => self.events.remove(key)?,
+            };
+            pruned += 1;
+        }
+
+        self.events.flush_async().await?;
+        Ok(pruned)
+    }
+
+    async fn migrate_secrets(&self) -> Result<u64, Error> {
+        let mut migrated = 0;
+        let keys: Vec<String> = self.secrets.iter().keys().filter_map(|key| String::from_utf8(key.ok()?.to_vec()).ok()).collect();
+
+        for key in keys.iter().filter(|key| !secret::is_digest(key)) {
+            self.rotate(key, &secret::digest(key)).await?;
+            migrated += 1;
+        }
+
+        for row in self.owners.iter() {
+            let (name, value) = row?;
+            let mut owner: Owner = serde_json::from_slice(&value)?;
+
+            if !secret::is_hash(&owner.token) {
+                owner.token = secret::hash(&owner.token).map_err(|err| anyhow!("{err}"))?;
+                self.owners.insert(name, serde_json::to_vec(&owner)?)?;
+                migrated += 1;
+            }
+        }
+
+        self.owners.flush_async().await?;
+        Ok(migrated)
+    }
+
+    async fn ping(&self) -> Result<(), Error> {
+        self.db.size_on_disk()?;
+        self.domains.first()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(name: &str) -> EmbeddedStore {
+        let path = std::env::temp_dir().join(format!("webx-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        EmbeddedStore::open(&path.to_string_lossy().to_string()).unwrap()
+    }
+
+    fn domain(key: &str) -> Domain { serde_json::from_value(serde_json::json!({ "name": "example", "tld": "dev", "ip": "1.2.3.4", "secret_key": key })).unwrap() }
+
+    #[tokio::test]
+    async fn insert_conflicts_on_taken_names() {
+        let store = store("conflict");
+        store.insert(&domain("a")).await.unwrap();
+
+        let err = store.insert(&domain("b")).await.unwrap_err();
+        assert!(err.is::<Conflict>());
+    }
+
+    #[tokio::test]
+    async fn rotated_keys_stop_working() {
+        let store = store("rotate");
+        store.insert(&domain("a")).await.unwrap();
+
+        assert!(store.rotate("a", "b").await.unwrap());
+        assert!(!store.rotate("a", "c").await.unwrap());
+        assert!(store.find_by_key("c").await.unwrap().is_none());
+        assert_eq!(store.find_by_key("b").await.unwrap().unwrap().secret_key.as_deref(), Some("b"));
+        assert!(!store.delete("a").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn transfers_replace_the_key() {
+        let store = store("transfer");
+        store.insert(&domain("a")).await.unwrap();
+
+        assert!(store.transfer("a", "team", "b").await.unwrap());
+        assert!(store.find_by_key("a").await.unwrap().is_none());
+        assert_eq!(store.find_by_key("b").await.unwrap().unwrap().owner.as_deref(), Some("team"));
+    }
+
+    #[tokio::test]
+    async fn replaced_domains_swap_keys() {
+        let store = store("replace");
+        store.insert(&domain("a")).await.unwrap();
+        assert!(!store.replace(&serde_json::from_value(serde_json::json!({ "name": "other", "tld": "dev", "ip": "1.2.3.4" })).unwrap()).await.unwrap());
+
+        let replacement = Domain { ip: "5.6.7.8".into(), ..domain("b") };
+        assert!(store.replace(&replacement).await.unwrap());
+        assert!(store.find_by_key("a").await.unwrap().is_none());
+        assert_eq!(store.find_by_key("b").await.unwrap().unwrap().ip, "5.6.7.8");
+    }
+
+    #[tokio::test]
+    async fn releases_keep_renewed_and_suspended_domains() {
+        let store = store("release");
+        store.insert(&Domain { expires: Some(1), ..domain("a") }).await.unwrap();
+
+        assert!(store.renew("a", 2).await.unwrap());
+        assert!(!store.delete_expired("a", 1).await.unwrap());
+
+        let suspension = Suspension {
+            reason: "phishing".into(),
+            since: 1,
+            by: "mod".into(),
+        };
+
+        assert!(store.set_suspension("a", Some(&suspension)).await.unwrap());
+        assert!(!store.delete_expired("a", 2).await.unwrap());
+
+        assert!(store.set_suspension("a", None).await.unwrap());
+        assert!(store.delete_expired("a", 2).await.unwrap());
+        assert!(store.find_by_key("a").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn events_after_the_last_id() {
+        let store = store("events");
+        let event: Event = serde_json::from_value(serde_json::json!({ "id": 0, "event": "created", "name": "example", "tld": "dev", "at": 1 })).unwrap();
+        store.append_event(event).await.unwrap();
+
+        assert_eq!(store.events_after(0, 10).await.unwrap().len(), 1);
+        assert!(store.events_after(u64::MAX, 10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn concurrent_changes_are_kept() {
+        let store = std::sync::Arc::new(store("modify"));
+        store.insert(&domain("a")).await.unwrap();
+
+        let suspension = Suspension {
+            reason: "phishing".into(),
+            since: 1,
+            by: "mod".into(),
+        };
+
+        let (owned, suspended) = tokio::join!(store.set_owner("a", "alice"), store.set_suspension("a", Some(&suspension)));
+        assert!(owned.unwrap() && suspended.unwrap());
+
+        let domain = store.find_by_key("a").await.unwrap().unwrap();
+        assert_eq!(domain.owner.as_deref(), Some("alice"));
+        assert!(domain.suspended.is_some());
+    }
+
+    #[tokio::test]
+    async fn updates_keep_suspensions() {
+        let store = std::sync::Arc::new(store("update"));
+        store.insert(&domain("a")).await.unwrap();
+
+        let suspension = Suspension {
+            reason: "phishing".into(),
+            since: 1,
+            by: "mod".into(),
+        };
+
+        let (updated, suspended) = tokio::join!(store.update("a", "5.6.7.8", &[], "test"), store.set_suspension("a", Some(&suspension)));
+        assert!(updated.unwrap() && suspended.unwrap());
+
+        let domain = store.find_by_key("a").await.unwrap().unwrap();
+        assert_eq!(domain.ip, "5.6.7.8");
+        assert!(domain.suspended.is_some());
+    }
+}
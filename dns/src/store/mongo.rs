@@ -0,0 +1,358 @@
+use super::{Conflict, DomainStore, Search};
+use crate::{
+    config::Config,
+    events::Event,
+    metrics::CommandTimer,
+    secret, time,
+    http::{Change, Cursor, Domain, Order, Owner, Record, Reservation, SortBy, Suspension, Verification},
+};
+use anyhow::{anyhow, Context, Error};
+use async_trait::async_trait;
+use futures::stream::{StreamExt, TryStreamExt};
+
+use mongodb::{
+    bson::{doc, to_bson, Document},
+    error::{ErrorKind, WriteFailure},
+    options::{ClientOptions, FindOneAndUpdateOptions, FindOptions, IndexOptions, ReplaceOptions, ReturnDocument},
+    Client, Collection, Database, IndexModel,
+};
+use std::sync::Arc;
+
+pub(crate) struct MongoStore {
+    database: Database,
+    db: Collection<Domain>,
+    owners: Collection<Owner>,
+    reserved: Collection<Reservation>,
+    history: Collection<Change>,
+    events: Collection<Event>,
+    counters: Collection<Document>,
+}
+
+impl MongoStore {
+    pub async fn connect(config: &Config) -> Result<Self, Error> {
+        let mongo = &config.server.mongo;
+        let mut client_options = ClientOptions::parse(&mongo.connection).await?;
+        client_options.app_name = Some(mongo.app_name.clone());
+        client_options.command_event_handler = Some(Arc::new(CommandTimer));
+
+        let client = Client::with_options(client_options)?;
+        let database = client.database(&mongo.db_name);
+
+        let db = database.collection::<Domain>("domains");
+
+        let owners = database.collection::<Owner>("owners");
+
+        // domains registered before creation times were recorded sort first
+        db.update_many(doc! { "created": { "$exists": false } }, doc! { "$set": { "created": 0_i64 } }, None).await?;
+
+        // registrations racing for a name or a key are settled by these, `insert` fails with a duplicate key error
+        db.create_index(unique(doc! { "name": 1, "tld": 1 }), None).await.context("Failed to index domains by name, remove duplicate domains first")?;
+        let keyed = IndexOptions::builder().unique(true).partial_filter_expression(doc! { "secret_key": { "$type": "string" } }).build();
+        db.create_index(IndexModel::builder().keys(doc! { "secret_key": 1 }).options(keyed).build(), None).await.context("Failed to index domains by key")?;
+        owners.create_index(unique(doc! { "name": 1 }), None).await.context("Failed to index owners by name, remove duplicate owners first")?;
+
+        log::info!("MongoDB server connected");
+        Ok(Self {
+            db,
+            owners,
+            reserved: database.collection::<Reservation>("reserved"),
+            history: database.collection::<Change>("history"),
+            events: database.collection::<Event>("events"),
+            counters: database.collection::<Document>("counters"),
+            database,
+        })
+    }
+}
+
+fn unique(keys: Document) -> IndexModel { IndexModel::builder().keys(keys).options(IndexOptions::builder().unique(true).build()).build() }
+
+/// Whether a write failed on a unique index
+fn is_duplicate(err: &mongodb::error::Error) -> bool { matches!(&*err.kind, ErrorKind::Write(WriteFailure::WriteError(error)) if error.code == 11000) }
+
+/// Match `text` literally inside a regex
+fn escape(text: &str) -> String {
+    text.chars().fold(String::new(), |mut out, char| {
+        if !char.is_ascii_alphanumeric() {
+            out.push('\\');
+        }
+        out.push(char);
+        out
+    })
+}
+
+fn search_filter(search: &Search, after: Option<&Cursor>) -> Document {
+    let mut clauses = Vec::new();
+
+    if let Some(tld) = &search.tld {
+        clauses.push(doc! { "tld": tld });
+    }
+    if let Some(prefix) = &search.prefix {
+        clauses.push(doc! { "name": { "$regex": format!("^{}", escape(prefix)) } });
+    }
+    if let Some(part) = &search.contains {
+        clauses.push(doc! { "name": { "$regex": escape(part) } });
+    }
+
+    if let Some(cursor) = after {
+        let op = match search.order {
+            Order::Asc => "$gt",
+            Order::Desc => "$lt",
+        };
+
+        let mut later = vec![doc! { "name": &cursor.name, "tld": { op: &cursor.tld } }, doc! { "name": { op: &cursor.name } }];
+        if search.sort == SortBy::Created {
+            let created = cursor.created as i64;
+            for clause in &mut later {
+                clause.insert("created", created);
+            }
+            later.push(doc! { "created": { op: created } });
+        }
+
+        clauses.push(doc! { "$or": later });
+    }
+
+    match clauses.is_empty() {
+        true => doc! {},
+        false => doc! { "$and": clauses },
+    }
+}
+
+fn search_sort(search: &Search) -> Document {
+    let direction = match search.order {
+        Order::Asc => 1,
+        Order::Desc => -1,
+    };
+
+    match search.sort {
+        SortBy::Name => doc! { "name": direction, "tld": direction },
+        SortBy::Created => doc! { "created": direction, "name": direction, "tld": direction },
+    }
+}
+
+#[async_trait]
+impl DomainStore for MongoStore {
+    async fn find(&self, name: &str, tld: &str) -> Result<Option<Domain>, Error> { Ok(self.db.find_one(doc! { "name": name, "tld": tld }, None).await?) }
+
+    async fn find_many(&self, names: &[(String, String)]) -> Result<Vec<Domain>, Error> {
+        let (mut labels, mut tlds): (Vec<&str>, Vec<&str>) = names.iter().map(|(name, tld)| (name.as_str(), tld.as_str())).unzip();
+        labels.sort_unstable();
+        labels.dedup();
+        tlds.sort_unstable();
+        tlds.dedup();
+
+        // matches every combination of the names and tlds, the pairs asked for are picked out after
+        let filter = doc! { "name": { "$in": labels }, "tld": { "$in": tlds } };
+        let domains: Vec<Domain> = self.db.find(filter, None).await?.try_collect().await?;
+
+        Ok(domains.into_iter().filter(|domain| names.iter().any(|(name, tld)| &domain.name == name && &domain.tld == tld)).collect())
+    }
+
+    async fn find_by_key(&self, key: &str) -> Result<Option<Domain>, Error> { Ok(self.db.find_one(doc! { "secret_key": key }, None).await?) }
+
+    async fn insert(&self, domain: &Domain) -> Result<(), Error> {
+        match self.db.insert_one(domain, None).await {
+            Ok(_) => Ok(()),
+            Err(err) if is_duplicate(&err) => Err(Conflict(format!("Domain {}.{}", domain.name, domain.tld)).into()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn update(&self, key: &str, ip: &str, records: &[Record], by: &str) -> Result<bool, Error> {
+        let now = time::now();
+        let update = doc! { "$set": { "ip": ip, "records": to_bson(records)?, "updated": now as i64 } };
+        let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::Before).build();
+
+        let Some(domain) = self.db.find_one_and_update(doc! { "secret_key": key }, update, options).await? else { return Ok(false) };
+
+        if domain.ip != ip {
+            let change = Change {
+                at: now,
+                by: by.to_string(),
+                old: domain.ip,
+                new: ip.to_string(),
+                name: domain.name,
+                tld: domain.tld,
+            };
+
+            self.history.insert_one(change, None).await?;
+        }
+
+        Ok(true)
+    }
+
+    async fn history(&self, name: &str, tld: &str) -> Result<Vec<Change>, Error> {
+        let options = FindOptions::builder().sort(doc! { "at": 1, "_id": 1 }).build();
+        let cursor = self.history.find(doc! { "name": name, "tld": tld }, options).await?;
+
+        Ok(cursor.filter_map(|result| async { result.ok() }).collect().await)
+    }
+
+    async fn replace(&self, domain: &Domain) -> Result<bool, Error> {
+        match self.db.replace_one(doc! { "name": &domain.name, "tld": &domain.tld }, domain, None).await {
+            Ok(result) => Ok(result.matched_count == 1),
+            Err(err) if is_duplicate(&err) => Err(Conflict(format!("Key of {}.{}", domain.name, domain.tld)).into()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn delete_expired(&self, key: &str, expires: u64) -> Result<bool, Error> {
+        let filter = doc! { "secret_key": key, "expires": expires as i64, "suspended": null };
+        Ok(self.db.delete_one(filter, None).await?.deleted_count == 1)
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, Error> {
+        let result = self.db.delete_one(doc! { "secret_key": key }, None).await?;
+        Ok(result.deleted_count == 1)
+    }
+
+    async fn search(&self, search: &Search) -> Result<(Vec<Domain>, u64), Error> {
+        let total = self.db.count_documents(search_filter(search, None), None).await?;
+        let options = FindOptions::builder().sort(search_sort(search)).skip(Some(search.skip)).limit(Some(search.limit as i64)).build();
+        let cursor = self.db.find(search_filter(search, search.after.as_ref()), options).await?;
+
+        Ok((cursor.filter_map(|result| async { result.ok() }).collect().await, total))
+    }
+
+    async fn rotate(&self, key: &str, new_key: &str) -> Result<bool, Error> {
+        let result = self.db.update_one(doc! { "secret_key": key }, doc! { "$set": { "secret_key": new_key } }, None).await?;
+        Ok(result.matched_count == 1)
+    }
+
+    async fn transfer(&self, key: &str, owner: &str, new_key: &str) -> Result<bool, Error> {
+        let result = self.db.update_one(doc! { "secret_key": key }, doc! { "$set": { "owner": owner, "secret_key": new_key } }, None).await?;
+        Ok(result.matched_count == 1)
+    }
+
+    async fn set_owner(&self, key: &str, owner: &str) -> Result<bool, Error> {
+        let result = self.db.update_one(doc! { "secret_key": key }, doc! { "$set": { "owner": owner } }, None).await?;
+        Ok(result.matched_count == 1)
+    }
+
+    async fn renew(&self, key: &str, expires: u64) -> Result<bool, Error> {
+        let result = self.db.update_one(doc! { "secret_key": key }, doc! { "$set": { "expires": expires as i64 } }, None).await?;
+        Ok(result.matched_count == 1)
+    }
+
+    async fn expired(&self, time: u64) -> Result<Vec<Domain>, Error> {
+        let cursor = self.db.find(doc! { "expires": { "$lt": time as i64 } }, None).await?;
+        Ok(cursor.filter_map(|result| async { result.ok() }).collect().await)
+    }
+
+    async fn set_unreachable(&self, key: &str, since: Option<u64>) -> Result<bool, Error> {
+        let update = match since {
+            Some(since) => doc! { "$set": { "unreachable": since as i64 } },
+            None => doc! { "$unset": { "unreachable": "" } },
+        };
+
+        let result = self.db.update_one(doc! { "secret_key": key }, update, None).await?;
+        Ok(result.matched_count == 1)
+    }
+
+    async fn set_suspension(&self, key: &str, suspension: Option<&Suspension>) -> Result<bool, Error> {
+        let update = match suspension {
+            Some(suspension) => doc! { "$set": { "suspended": to_bson(suspension)? } },
+            None => doc! { "$unset": { "suspended": "" } },
+        };
+
+        let result = self.db.update_one(doc! { "secret_key": key }, update, None).await?;
+        Ok(result.matched_count == 1)
+    }
+
+    async fn set_verification(&self, key: &str, verification: &Verification) -> Result<bool, Error> {
+        let update = doc! { "$set": { "verification": to_bson(verification)? } };
+
+        let result = self.db.update_one(doc! { "secret_key": key }, update, None).await?;
+        Ok(result.matched_count == 1)
+    }
+
+    async fn find_by_owner(&self, owner: &str) -> Result<Vec<Domain>, Error> {
+        let cursor = self.db.find(doc! { "owner": owner }, None).await?;
+        Ok(cursor.filter_map(|result| async { result.ok() }).collect().await)
+    }
+
+    async fn find_owner(&self, name: &str) -> Result<Option<Owner>, Error> { Ok(self.owners.find_one(doc! { "name": name }, None).await?) }
+
+    async fn insert_owner(&self, owner: &Owner) -> Result<(), Error> {
+        match self.owners.insert_one(owner, None).await {
+            Ok(_) => Ok(()),
+            Err(err) if is_duplicate(&err) => Err(Conflict(format!("Owner {}", owner.name)).into()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn find_reservation(&self, name: &str, tld: &str) -> Result<Option<Reservation>, Error> {
+        let filter = doc! { "name": name, "$or": [{ "tld": tld }, { "tld": null }] };
+        Ok(self.reserved.find_one(filter, None).await?)
+    }
+
+    async fn find_reservations(&self, names: &[String]) -> Result<Vec<Reservation>, Error> {
+        Ok(self.reserved.find(doc! { "name": { "$in": names } }, None).await?.try_collect().await?)
+    }
+
+    async fn reserve(&self, reservation: &Reservation) -> Result<(), Error> {
+        let filter = doc! { "name": &reservation.name, "tld": to_bson(&reservation.tld)? };
+        let options = ReplaceOptions::builder().upsert(true).build();
+
+        self.reserved.replace_one(filter, reservation, options).await?;
+        Ok(())
+    }
+
+    async fn release(&self, name: &str, tld: Option<&str>) -> Result<bool, Error> {
+        let result = self.reserved.delete_one(doc! { "name": name, "tld": tld }, None).await?;
+        Ok(result.deleted_count == 1)
+    }
+
+    async fn reservations(&self) -> Result<Vec<Reservation>, Error> {
+        let cursor = self.reserved.find(None, None).await?;
+        Ok(cursor.filter_map(|result| async { result.ok() }).collect().await)
+    }
+
+    async fn append_event(&self, mut event: Event) -> Result<Event, Error> {
+        let options = FindOneAndUpdateOptions::builder().upsert(true).return_document(ReturnDocument::After).build();
+        let counter = self.counters.find_one_and_update(doc! { "_id": "events" }, doc! { "$inc": { "seq": 1_i64 } }, options).await?;
+
+        event.id = counter.and_then(|counter| counter.get_i64("seq").ok()).ok_or_else(|| anyhow!("Event counter is missing"))? as u64;
+        self.events.insert_one(&event, None).await?;
+        Ok(event)
+    }
+
+    async fn events_after(&self, id: u64, limit: u64) -> Result<Vec<Event>, Error> {
+        let options = FindOptions::builder().sort(doc! { "id": 1 }).limit(Some(limit as i64)).build();
+        // ids past the range of BSON integers would wrap around and replay everything
+        let Ok(id) = i64::try_from(id) else { return Ok(Vec::new()) };
+        let cursor = self.events.find(doc! { "id": { "$gt": id } }, options).await?;
+
+        Ok(cursor.filter_map(|result| async { result.ok() }).collect().await)
+    }
+
+    async fn prune_events(&self, time: u64) -> Result<u64, Error> { Ok(self.events.delete_many(doc! { "at": { "$lt": time as i64 } }, None).await?.deleted_count) }
+
+    async fn migrate_secrets(&self) -> Result<u64, Error> {
+        let mut migrated = 0;
+        let mut domains = self.db.find(None, None).await?;
+
+        while let Some(domain) = domains.try_next().await? {
+            if let Some(key) = domain.secret_key.filter(|key| !secret::is_digest(key)) {
+                self.rotate(&key, &secret::digest(&key)).await?;
+                migrated += 1;
+            }
+        }
+
+        let mut owners = self.owners.find(None, None).await?;
+
+        while let Some(owner) = owners.try_next().await? {
+            if !secret::is_hash(&owner.token) {
+                let token = secret::hash(&owner.token).map_err(|err| anyhow!("{err}"))?;
+                self.owners.update_one(doc! { "name": &owner.name }, doc! { "$set": { "token": token } }, None).await?;
+                migrated += 1;
+            }
+        }
+
+        Ok(migrated)
+    }
+
+    async fn ping(&self) -> Result<(), Error> {
+        self.database.run_command(doc! { "ping": 1 }, None).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,13 @@
+use chrono::DateTime;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Current unix timestamp in seconds
+pub fn now() -> u64 { SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs() }
+
+/// Render a unix timestamp for humans, `-` when unset
+pub fn format(timestamp: Option<u64>) -> String {
+    timestamp
+        .and_then(|ts| DateTime::from_timestamp(ts as i64, 0))
+        .map(|date| date.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_else(|| "-".into())
+}
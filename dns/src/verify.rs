@@ -0,0 +1,119 @@
+use crate::{config::Config, net};
+use anyhow::{anyhow, Error};
+use reqwest::{redirect::Policy, Url};
+use std::time::Duration;
+
+/// File holding the token, at the root of GitHub repositories and under `/.well-known` elsewhere
+const FILE: &str = "webx-verification.txt";
+
+/// Largest proof read, tokens are far shorter
+const MAX_PROOF: usize = 1024;
+
+/// Where the token proving control over `target` is looked for, `None` when it is not an http(s) URL
+pub(crate) fn proof_url(target: &str, config: &Config) -> Option<String> {
+    let url = Url::parse(target).ok().filter(|url| matches!(url.scheme(), "http" | "https"))?;
+
+    if matches!(url.host_str()?, "github.com" | "www.github.com") {
+        let mut segments = url.path_segments()?.filter(|segment| !segment.is_empty());
+        let (owner, repo) = (segments.next()?, segments.next()?);
+        let raw = config.settings.verification.github_raw.trim_end_matches('/');
+
+        return Some(format!("{raw}/{owner}/{}/HEAD/{FILE}", repo.trim_end_matches(".git")));
+    }
+
+    Some(format!("{}/.well-known/{FILE}", url.origin().ascii_serialization()))
+}
+
+/// Fetch the proof at `url`, succeeding when it holds exactly `token`
+pub(crate) async fn check(url: &str, token: &str, config: &Config) -> Result<(), Error> {
+    let settings = &config.settings.verification;
+    let parsed = Url::parse(url)?;
+    let host = parsed.host_str().ok_or_else(|| anyhow!("{url} has no host"))?;
+    let addr = net::resolve(host, parsed.port_or_known_default().unwrap_or(80), settings.allow_private).await?;
+
+    // redirects could point the check at a target the holder does not control,
+    // and the connection is pinned to the checked address so it cannot be resolved again elsewhere
+    let mut builder = reqwest::Client::builder().timeout(Duration::from_secs(settings.timeout)).redirect(Policy::none());
+    if let Some(domain) = parsed.domain() {
+        builder = builder.resolve(domain, addr);
+    }
+
+    // upstream answers and errors are not passed on, they would turn the check into a probe
+    let mut response = builder.build()?.get(parsed).send().await.map_err(|err| {
+        log::debug!("Verification fetch of {url} failed: {}", err.without_url());
+        anyhow!("Could not fetch {url}")
+    })?;
+
+    if !response.status().is_success() {
+        log::debug!("Verification fetch of {url} answered {}", response.status());
+        return Err(anyhow!("{url} does not hold the verification token"));
+    }
+
+    let mut proof = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(|_| anyhow!("Could not fetch {url}"))? {
+        proof.extend_from_slice(&chunk);
+
+        if proof.len() > MAX_PROOF {
+            return Err(anyhow!("{url} is larger than {MAX_PROOF} bytes"));
+        }
+    }
+
+    match String::from_utf8_lossy(&proof).trim() == token {
+        true => Ok(()),
+        false => Err(anyhow!("{url} does not hold the verification token")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// Serve `status` and `body` to a single request on a local port, returning the proof url
+    async fn stand_in(status: &'static str, body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await;
+            let response = format!("HTTP/1.1 {status}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}", body.len());
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        format!("http://{addr}/.well-known/{FILE}")
+    }
+
+    fn config(allow_private: bool) -> Config {
+        let mut config = Config::new();
+        config.settings.verification.allow_private = allow_private;
+        config
+    }
+
+    #[tokio::test]
+    async fn served_token_verifies() {
+        let url = stand_in("200 OK", "token\n").await;
+        assert!(check(&url, "token", &config(true)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn upstream_status_is_not_echoed() {
+        let url = stand_in("418 I'm a teapot", "token").await;
+        let err = check(&url, "token", &config(true)).await.unwrap_err().to_string();
+
+        assert!(!err.contains("418"), "{err}");
+        assert!(err.contains("does not hold the verification token"));
+    }
+
+    #[tokio::test]
+    async fn private_targets_are_refused() {
+        for url in ["http://127.0.0.1/", "http://169.254.169.254/latest/meta-data", "http://10.0.0.1/", "http://[::1]/", "http://[fd00::1]/", "http://localhost/"] {
+            let err = check(url, "token", &config(false)).await.unwrap_err().to_string();
+            assert!(err.contains("public address"), "{url}: {err}");
+        }
+    }
+}
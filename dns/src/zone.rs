@@ -0,0 +1,616 @@
+use crate::{
+    config::Config,
+    events::{EventKind, Events},
+    http::{check_records, check_target, is_offensive, Cursor, Domain, Order, Record, RecordKind, SortBy},
+    naming, secret,
+    store::{DomainStore, Search},
+    time,
+};
+use anyhow::{anyhow, Error};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::Arc,
+};
+
+const PAGE_SIZE: u64 = 500;
+
+/// RR parameters written for URL records, served as URI records by the DNS listener
+const URI_PRIORITY: u16 = 10;
+const URI_WEIGHT: u16 = 1;
+
+/// SOA refresh, retry and expire of every tld, in seconds
+pub(crate) const SOA_TIMERS: (u32, u32, u32) = (3600, 600, 604800);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// One JSON domain per line, with every field needed to restore it
+    Jsonl,
+    /// BIND style zone files, one per tld, holding only the records
+    Bind,
+}
+
+/// What to do with imported domains that are already registered
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Conflict {
+    /// Keep the registered domain
+    Skip,
+    /// Replace the registered domain
+    Overwrite,
+    /// Import nothing when any domain is already registered
+    Abort,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Action {
+    Create,
+    Overwrite,
+    Skip,
+}
+
+/// A domain read from an import, with what will happen to it
+pub struct Planned {
+    pub domain: Domain,
+    pub action: Action,
+    existing: Option<Domain>,
+}
+
+/// Every domain, only those on `tld` when given, in name order
+pub async fn domains(db: &dyn DomainStore, tld: Option<&str>) -> Result<Vec<Domain>, Error> {
+    let (mut domains, mut after) = (Vec::new(), None);
+
+    loop {
+        let search = Search {
+            tld: tld.map(str::to_string),
+            prefix: None,
+            contains: None,
+            sort: SortBy::Name,
+            order: Order::Asc,
+            after: after.take(),
+            skip: 0,
+            limit: PAGE_SIZE,
+        };
+
+        let (page, _) = db.search(&search).await?;
+        after = page.last().map(Cursor::of);
+        domains.extend(page);
+
+        if after.is_none() {
+            return Ok(domains);
+        }
+    }
+}
+
+pub fn write_jsonl(domains: &[Domain], path: &str) -> Result<(), Error> {
+    let mut out = BufWriter::new(File::create(path)?);
+
+    for domain in domains {
+        serde_json::to_writer(&mut out, domain)?;
+        out.write_all(b"\n")?;
+    }
+
+    Ok(out.flush()?)
+}
+
+pub fn read_jsonl(path: &str) -> Result<Vec<Domain>, Error> {
+    let mut domains = Vec::new();
+
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        domains.push(serde_json::from_str(&line).map_err(|err| anyhow!("{path}:{}: {err}", number + 1))?);
+    }
+
+    Ok(domains)
+}
+
+/// Write `<tld>.zone` into `dir` for every tld with domains, returning the files written
+pub fn write_bind(domains: &[Domain], dir: &str, config: &Config) -> Result<Vec<String>, Error> {
+    let dns = &config.server.dns;
+    let (ttl, (refresh, retry, expire)) = (dns.ttl, SOA_TIMERS);
+
+    let mut zones: BTreeMap<&str, Vec<&Domain>> = BTreeMap::new();
+    for domain in domains {
+        zones.entry(&domain.tld).or_default().push(domain);
+    }
+
+    fs::create_dir_all(dir)?;
+    let mut written = Vec::new();
+
+    for (tld, domains) in zones {
+        let path = Path::new(dir).join(format!("{tld}.zone")).to_string_lossy().into_owned();
+        let mut out = BufWriter::new(File::create(&path)?);
+
+        writeln!(out, "; .{tld} exported by webx_dns v{} at {}", env!("CARGO_PKG_VERSION"), time::format(Some(time::now())))?;
+        writeln!(out, "$ORIGIN {tld}.\n$TTL {ttl}\n")?;
+
+        // the serial follows the last change of the zone, so unchanged zones export the same
+        let serial = domains.iter().map(|domain| domain.updated.max(domain.created)).max().unwrap_or_default() as u32;
+        let (nameserver, hostmaster) = (dns.nameserver.trim_end_matches('.'), dns.hostmaster.trim_end_matches('.'));
+        writeln!(out, "@ IN SOA {nameserver}. {hostmaster}. ( {serial} {refresh} {retry} {expire} {ttl} )")?;
+        writeln!(out, "@ IN NS {nameserver}.")?;
+
+        for domain in domains {
+            let records = domain.records();
+            writeln!(out)?;
+
+            if records.is_empty() {
+                writeln!(out, "; {} has no records", domain.name)?;
+            }
+
+            for record in records {
+                let owner = match record.name.as_str() {
+                    "@" => domain.name.clone(),
+                    host => format!("{host}.{}", domain.name),
+                };
+
+                let ttl = record.ttl.map(|ttl| ttl.to_string()).unwrap_or_default();
+                let (kind, data) = rdata(&record);
+                writeln!(out, "{owner:<24} {ttl:>6} IN {kind:<5} {data}")?;
+            }
+        }
+
+        out.flush()?;
+        written.push(path);
+    }
+
+    Ok(written)
+}
+
+/// Type and presentation format rdata of a record
+fn rdata(record: &Record) -> (&'static str, String) {
+    match record.kind {
+        RecordKind::A => ("A", record.value.clone()),
+        RecordKind::Aaaa => ("AAAA", record.value.clone()),
+        RecordKind::Txt => ("TXT", character_strings(&record.value)),
+        RecordKind::Url => ("URI", format!("{URI_PRIORITY} {URI_WEIGHT} {}", quote(&record.value))),
+    }
+}
+
+fn quote(value: &str) -> String { format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")) }
+
+/// Quoted strings of at most 255 bytes, split on character boundaries
+fn character_strings(value: &str) -> String {
+    let mut chunks = vec![String::new()];
+
+    for char in value.chars() {
+        if chunks.last().is_some_and(|chunk| chunk.len() + char.len_utf8() > 255) {
+            chunks.push(String::new());
+        }
+        chunks.last_mut().unwrap().push(char);
+    }
+
+    chunks.iter().map(|chunk| quote(chunk)).collect::<Vec<_>>().join(" ")
+}
+
+/// Zone files to import, every `*.zone` file when `path` is a directory
+pub fn zone_files(path: &str) -> Result<Vec<String>, Error> {
+    if !Path::new(path).is_dir() {
+        return Ok(vec![path.to_string()]);
+    }
+
+    let mut files: Vec<String> = fs::read_dir(path)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "zone"))
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+
+    files.sort();
+    Ok(files)
+}
+
+/// Split a line into fields, keeping quoted strings together and dropping comments
+fn tokenize(line: &str) -> Result<Vec<String>, Error> {
+    let (mut tokens, mut chars) = (Vec::new(), line.chars().peekable());
+
+    while let Some(&char) = chars.peek() {
+        match char {
+            ';' => break,
+            char if char.is_whitespace() || char == '(' || char == ')' => {
+                chars.next();
+            }
+            '"' => {
+                chars.next();
+                let mut token = String::from('"');
+
+                loop {
+                    match chars.next() {
+                        Some('\\') => token.extend(chars.next()),
+                        Some('"') => break,
+                        Some(char) => token.push(char),
+                        None => return Err(anyhow!("unterminated string")),
+                    }
+                }
+                tokens.push(token);
+            }
+            _ => {
+                let mut token = String::new();
+                while let Some(&char) = chars.peek() {
+                    if char.is_whitespace() || matches!(char, ';' | '"' | '(' | ')') {
+                        break;
+                    }
+                    token.push(char);
+                    chars.next();
+                }
+                tokens.push(token);
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Whether `line` opens more parentheses than it closes, ignoring quoted strings and comments
+fn unbalanced(line: &str) -> bool {
+    let (mut depth, mut quoted, mut chars) = (0i32, false, line.chars());
+
+    while let Some(char) = chars.next() {
+        match char {
+            '\\' if quoted => {
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ';' if !quoted => break,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            _ => {}
+        }
+    }
+
+    depth > 0
+}
+
+/// Strip the opening quote `tokenize` leaves on quoted strings
+fn unquote(token: &str) -> &str { token.strip_prefix('"').unwrap_or(token) }
+
+/// Read the domains of a zone file. Records are grouped by the label right below the
+/// `$ORIGIN` tld, unsupported types are skipped with a warning.
+pub fn read_bind(path: &str) -> Result<Vec<Domain>, Error> {
+    let content = fs::read_to_string(path)?;
+    let (mut origin, mut owner): (Option<String>, Option<String>) = (None, None);
+    let mut domains: BTreeMap<String, Vec<Record>> = BTreeMap::new();
+    let mut lines = content.lines().enumerate();
+
+    while let Some((number, line)) = lines.next() {
+        let error = |err: String| anyhow!("{path}:{}: {err}", number + 1);
+        let mut line = line.to_string();
+
+        // records spanning several lines, like SOA, are wrapped in parentheses
+        while unbalanced(&line) {
+            match lines.next() {
+                Some((_, next)) => line = format!("{line} {next}"),
+                None => return Err(error("unbalanced parentheses".into())),
+            }
+        }
+
+        let mut tokens = tokenize(&line).map_err(|err| error(err.to_string()))?;
+        if tokens.is_empty() {
+            continue;
+        }
+
+        match tokens[0].to_uppercase().as_str() {
+            "$ORIGIN" => {
+                let name = tokens.get(1).ok_or_else(|| error("$ORIGIN without a name".into()))?;
+                origin = Some(name.trim_end_matches('.').to_lowercase());
+                continue;
+            }
+            "$TTL" => continue,
+            directive if directive.starts_with('$') => return Err(error(format!("unsupported directive {directive}"))),
+            _ => {}
+        }
+
+        // lines starting with whitespace belong to the previous owner
+        if !line.starts_with(char::is_whitespace) {
+            owner = Some(tokens.remove(0));
+        }
+
+        let origin = origin.as_deref().ok_or_else(|| error("record before $ORIGIN".into()))?;
+        let owner = owner.as_deref().ok_or_else(|| error("record without an owner".into()))?.to_lowercase();
+
+        let mut ttl = None;
+        while let Some(token) = tokens.first() {
+            match token.parse::<u32>() {
+                Ok(value) => ttl = Some(value),
+                Err(_) if token.eq_ignore_ascii_case("IN") => {}
+                Err(_) => break,
+            }
+            tokens.remove(0);
+        }
+
+        let Some(kind) = tokens.first().map(|kind| kind.to_uppercase()) else { return Err(error("record without a type".into())) };
+        let data = &tokens[1..];
+
+        let relative = match owner.strip_suffix('.') {
+            Some(absolute) if absolute == origin => "@".to_string(),
+            Some(absolute) => match absolute.strip_suffix(&format!(".{origin}")) {
+                Some(relative) => relative.to_string(),
+                None => return Err(error(format!("{owner} is outside of {origin}"))),
+            },
+            None => owner.clone(),
+        };
+
+        // the SOA and NS of the tld come from the config, not from imports
+        if relative == "@" {
+            if !matches!(kind.as_str(), "SOA" | "NS") {
+                log::warn!("{path}:{}: skipping {kind} record of .{origin} itself", number + 1);
+            }
+            continue;
+        }
+
+        let (host, name) = relative.rsplit_once('.').unwrap_or(("@", &relative));
+
+        let (kind, value) = match (kind.as_str(), data) {
+            ("A", [value]) => (RecordKind::A, value.to_string()),
+            ("AAAA", [value]) => (RecordKind::Aaaa, value.to_string()),
+            ("TXT", strings) if !strings.is_empty() => (RecordKind::Txt, strings.iter().map(|string| unquote(string)).collect()),
+            ("URI", [_, _, target]) => (RecordKind::Url, unquote(target).to_string()),
+            _ => {
+                log::warn!("{path}:{}: skipping unsupported {kind} record of {relative}", number + 1);
+                continue;
+            }
+        };
+
+        domains.entry(format!("{name}.{origin}")).or_default().push(Record {
+            name: host.to_string(),
+            kind,
+            value,
+            ttl,
+        });
+    }
+
+    Ok(domains
+        .into_iter()
+        .filter_map(|(domain, records)| {
+            let (name, tld) = domain.split_once('.')?;
+            let Some(ip) = records.iter().find(|record| record.is_apex() && record.kind.is_target()).map(|record| record.value.clone()) else {
+                log::warn!("{path}: skipping {domain}, it has no A, AAAA or URI record of its own");
+                return None;
+            };
+
+            Some(Domain {
+                name: name.to_string(),
+                tld: tld.to_string(),
+                ip,
+                records,
+                secret_key: None,
+                owner: None,
+                suspended: None,
+                created: 0,
+                updated: 0,
+                expires: None,
+                unreachable: None,
+                verification: None,
+            })
+        })
+        .collect())
+}
+
+/// Decide what happens to every imported domain, failing on domains that can not be imported
+pub async fn plan(config: &Config, db: &dyn DomainStore, domains: Vec<Domain>, conflict: Conflict) -> Result<Vec<Planned>, Error> {
+    let mut planned: Vec<Planned> = Vec::new();
+
+    for mut domain in domains {
+        if config.tld(&domain.tld).is_none() {
+            return Err(anyhow!("{}.{} is on a tld that is not configured", domain.name, domain.tld));
+        }
+
+        // imports go through the same checks as the API
+        domain.name = naming::validate(&domain.name, &config.naming_for(&domain.tld)).map_err(|violation| anyhow!("{}.{} is not a valid name: {}", domain.name, domain.tld, violation.message()))?;
+
+        if is_offensive(&domain.name, config) {
+            return Err(anyhow!("{}.{} is offensive", domain.name, domain.tld));
+        }
+
+        let invalid = |err: String| anyhow!("{}.{} has invalid records: {err}", domain.name, domain.tld);
+        let (ip, records) = check_records(&domain.ip, &domain.records).map_err(invalid)?;
+        check_target(&ip).map_err(invalid)?;
+        (domain.ip, domain.records) = (ip, records);
+
+        if planned.iter().any(|other| other.domain.name == domain.name && other.domain.tld == domain.tld) {
+            return Err(anyhow!("{}.{} is imported more than once", domain.name, domain.tld));
+        }
+
+        let existing = db.find(&domain.name, &domain.tld).await?;
+        let reserved = config.tld(&domain.tld).is_some_and(|policy| policy.reserves(&domain.name)) || db.find_reservation(&domain.name, &domain.tld).await?.is_some();
+
+        // reservations keep names from being registered, domains already holding them can still be overwritten
+        if existing.is_none() && reserved {
+            return Err(anyhow!("{}.{} is reserved", domain.name, domain.tld));
+        }
+
+        let action = match (&existing, conflict) {
+            (None, _) => Action::Create,
+            (Some(_), Conflict::Skip) => Action::Skip,
+            (Some(_), Conflict::Overwrite) => Action::Overwrite,
+            (Some(_), Conflict::Abort) => return Err(anyhow!("{}.{} is already registered", domain.name, domain.tld)),
+        };
+
+        planned.push(Planned { domain, action, existing });
+    }
+
+    Ok(planned)
+}
+
+/// Apply an import plan, returning the plaintext keys generated for domains that came without one
+pub async fn apply(config: &Config, db: Arc<dyn DomainStore>, planned: Vec<Planned>, format: Format) -> Result<Vec<(String, String)>, Error> {
+    let (events, mut keys) = (Events::new(db.clone()), Vec::new());
+
+    for Planned { mut domain, action, existing } in planned {
+        let kind = match action {
+            Action::Skip => continue,
+            Action::Create => EventKind::Created,
+            Action::Overwrite => EventKind::Updated,
+        };
+
+        // zone files only carry records, everything else is kept from the registered domain or starts out like a registration
+        match (format, &existing) {
+            (Format::Bind, Some(existing)) => {
+                domain = Domain {
+                    ip: domain.ip,
+                    records: domain.records,
+                    updated: time::now(),
+                    ..existing.clone()
+                }
+            }
+            (Format::Bind, None) => {
+                (domain.created, domain.updated) = (time::now(), time::now());
+                domain.expires = config.tld(&domain.tld).and_then(|policy| policy.lifetime()).map(|lifetime| domain.created + lifetime);
+            }
+            (Format::Jsonl, _) => {}
+        }
+
+        domain.owner = domain.owner.or_else(|| existing.as_ref().and_then(|existing| existing.owner.clone()));
+        domain.secret_key = match domain.secret_key.take().or_else(|| existing.as_ref().and_then(|existing| existing.secret_key.clone())) {
+            Some(key) => Some(key),
+            None => {
+                let key = secret::generate(31);
+                keys.push((format!("{}.{}", domain.name, domain.tld), key.clone()));
+                Some(secret::digest(&key))
+            }
+        };
+
+        // overwritten domains are replaced at once, so a failure can not leave the name unregistered
+        match existing {
+            Some(_) if !db.replace(&domain).await? => return Err(anyhow!("{}.{} was deleted during the import", domain.name, domain.tld)),
+            Some(_) => {}
+            None => db.insert(&domain).await?,
+        }
+
+        events.emit(kind, &domain).await;
+    }
+
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("webx-zone-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Read `content` as the zone file `name`
+    fn read(name: &str, content: &str) -> Result<Vec<Domain>, Error> {
+        let path = format!("{}/{name}.zone", dir(name));
+        fs::write(&path, content).unwrap();
+        read_bind(&path)
+    }
+
+    fn record(kind: RecordKind, name: &str, value: &str, ttl: Option<u32>) -> Record {
+        Record {
+            kind,
+            name: name.into(),
+            value: value.into(),
+            ttl,
+        }
+    }
+
+    #[test]
+    fn tokens() {
+        assert_eq!(tokenize("www 300 IN A 1.2.3.4 ; comment").unwrap(), ["www", "300", "IN", "A", "1.2.3.4"]);
+        assert_eq!(tokenize(r#"@ TXT "a \"quoted\"; value" "second""#).unwrap(), ["@", "TXT", "\"a \"quoted\"; value", "\"second"]);
+        assert_eq!(tokenize("@ SOA ns. host. ( 1 2 3 4 5 )").unwrap().len(), 9);
+        assert!(tokenize(r#"@ TXT "unterminated"#).is_err());
+    }
+
+    #[test]
+    fn zone_files() {
+        let zone = r#"
+; a comment line
+$ORIGIN dev.
+$TTL 300
+@ IN SOA ns1.example.com. hostmaster.example.com. (
+    1 3600 600 604800 300 ) ; spanning lines
+@ IN NS ns1.example.com.
+
+example 60 IN A 1.2.3.4
+        IN TXT "hello; (world)" "again"
+www.example IN AAAA 2001:db8::1
+other.dev. IN URI 10 1 "https://example.com"
+other IN MX 10 mail.example.com.
+"#;
+
+        let domains = read("read", zone).unwrap();
+        assert_eq!(domains.len(), 2);
+
+        let example = &domains[0];
+        assert_eq!((example.name.as_str(), example.tld.as_str(), example.ip.as_str()), ("example", "dev", "1.2.3.4"));
+        assert_eq!(
+            example.records,
+            [
+                record(RecordKind::A, "@", "1.2.3.4", Some(60)),
+                record(RecordKind::Txt, "@", "hello; (world)again", None),
+                record(RecordKind::Aaaa, "www", "2001:db8::1", None),
+            ]
+        );
+
+        // absolute names, and types the registry does not serve are skipped
+        assert_eq!(domains[1].records, [record(RecordKind::Url, "@", "https://example.com", None)]);
+    }
+
+    #[test]
+    fn malformed_zone_files() {
+        let errors = [
+            ("before", "example IN A 1.2.3.4\n"),
+            ("directive", "$INCLUDE other.zone\n"),
+            ("outside", "$ORIGIN dev.\nexample.web. IN A 1.2.3.4\n"),
+            ("unterminated", "$ORIGIN dev.\nexample IN TXT \"open\n"),
+            ("parentheses", "$ORIGIN dev.\n@ IN SOA ns. host. ( 1 2\n"),
+            ("untyped", "$ORIGIN dev.\nexample 300 IN\n"),
+        ];
+
+        for (name, zone) in errors {
+            let err = read(name, zone).unwrap_err().to_string();
+            assert!(err.contains(&format!("{name}.zone:")), "{name}: {err}");
+        }
+    }
+
+    #[test]
+    fn exports_import_unchanged() {
+        let domain: Domain = serde_json::from_value(serde_json::json!({
+            "name": "example",
+            "tld": "dev",
+            "ip": "https://example.com/path",
+            "updated": 1718000000,
+            "records": [
+                { "type": "URL", "name": "@", "value": "https://example.com/path" },
+                { "type": "A", "name": "@", "value": "1.2.3.4", "ttl": 60 },
+                { "type": "TXT", "name": "@", "value": format!("\"quoted\" (open {}", "x".repeat(300)) },
+                { "type": "AAAA", "name": "www.blog", "value": "2001:db8::1" }
+            ]
+        }))
+        .unwrap();
+
+        let dir = dir("export");
+        let written = write_bind(std::slice::from_ref(&domain), &dir, &Config::new()).unwrap();
+        let zone = fs::read_to_string(&written[0]).unwrap();
+        assert!(zone.contains("$ORIGIN dev.") && zone.contains("@ IN SOA localhost. hostmaster.localhost. ( 1718000000 ") && zone.contains("@ IN NS localhost."));
+
+        let imported = read_bind(&written[0]).unwrap();
+        assert_eq!(imported.len(), 1);
+        assert_eq!((imported[0].name.as_str(), imported[0].ip.as_str()), ("example", "https://example.com/path"));
+        assert_eq!(imported[0].records, domain.records);
+    }
+
+    #[tokio::test]
+    async fn imports_follow_the_naming_rules() {
+        let db = crate::store::EmbeddedStore::open(&dir("naming")).unwrap();
+        let domain = |name: &str| -> Domain { serde_json::from_value(serde_json::json!({ "name": name, "tld": "dev", "ip": "1.2.3.4" })).unwrap() };
+
+        for name in ["a.b", "bad_name", "-dash"] {
+            assert!(plan(&Config::new(), &db, vec![domain(name)], Conflict::Abort).await.is_err(), "{name}");
+        }
+
+        let planned = plan(&Config::new(), &db, vec![domain("Example")], Conflict::Abort).await.unwrap();
+        assert_eq!(planned[0].domain.name, "example");
+    }
+}
@@ -588,12 +588,15 @@ fn fetch_dns(url: String) -> String {
 
     let client: reqwest::blocking::ClientBuilder = reqwest::blocking::Client::builder();
 
+    // subdomains stay part of the name, e.g. blog.example.dev -> /domain/blog.example/dev
+    let host = url.split('/').next().unwrap_or("");
+    let (name, tld) = host.rsplit_once('.').unwrap_or((host, ""));
+
     let clienturl = format!(
         "{}/domain/{}/{}",
         DNS_SERVER.lock().unwrap().as_str(),
-        url.split('.').next().unwrap_or(""),
-        url.split('.').nth(1).unwrap_or("")
-            .split('/').next().unwrap_or(""),
+        name,
+        tld,
     );
 
     